@@ -11,8 +11,8 @@ if [ "$1" = "testnet" ]; then
 fi
 
 CONTRACTS=(
-    "long-token"
-    "short-token"
+    "claim-token"
+    "position-token"
     "fee-collector"
     "oracle-router"
     "forward-market"
@@ -23,6 +23,30 @@ export interface MarketParams {
   mint_fee_bps: number;
   settle_fee_bps: number;
   redeem_fee_bps: number;
+  caps?: MarketCaps | null;
+}
+
+// Unset limits are not enforced; a window of 0 seconds caps minting per block
+export interface MarketCaps {
+  max_total_collateral?: string | null;
+  max_mint_per_account?: string | null;
+  max_mint_per_window?: string | null;
+  window_secs?: number;
+}
+
+export interface MarketConfig {
+  oracle: string;
+  fee_collector: string;
+  guardian: string;
+}
+
+export type MarketStatus = 'Active' | 'Matured' | 'Settled' | 'Archived';
+
+export type TokenBackend = 'Nep141' | 'Nep245';
+
+export interface MarketPage {
+  markets: MarketInfo[];
+  next_cursor: string | null;
 }
 
 export interface MarketInfo {
@@ -32,6 +56,96 @@ export interface MarketInfo {
   params: MarketParams;
   created_at: string;
   creator: string;
+  code_version: string;
+  config: MarketConfig;
+  status: MarketStatus;
+  series_id: string | null;
+  bond: string;
+  bond_slashed: boolean;
+  token_backend: TokenBackend;
+}
+
+export interface RelativeMarketParams {
+  underlying: string;
+  quote: string;
+  maturity: string;
+  lower_offset_bps: number;
+  upper_offset_bps: number;
+  tick_size: string;
+  mint_fee_bps: number;
+  settle_fee_bps: number;
+  redeem_fee_bps: number;
+}
+
+export interface ParamLimits {
+  min_fee_bps: number;
+  max_fee_bps: number;
+  min_bound_width_bps: number;
+  max_bound_width_bps: number;
+  min_time_to_maturity_secs: number;
+  max_time_to_maturity_secs: number;
+}
+
+export interface UnderlyingListing {
+  underlying: string;
+  quotes: string[];
+}
+
+export interface ClaimMetadata {
+  side: 'Long' | 'Short';
+  market: string;
+  terms: {
+    maturity: number;
+    strike_k: string;
+    lower_bound_l: string;
+    upper_bound_u: string;
+  } | null;
+  name: string;
+  symbol: string;
+  icon: string | null;
+  reference: string | null;
+  decimals: number;
+  settlement_factor: string | null;
+  payout_per_token: string | null;
+  restrict_transfers: boolean;
+}
+
+export interface MarketSeries {
+  series_id: string;
+  underlying: string;
+  quote: string;
+  cadence_secs: number;
+  roll_lead_secs: number;
+  next_maturity: number;
+  strike_k: string;
+  lower_width_bps: number;
+  upper_width_bps: number;
+  mint_fee_bps: number;
+  settle_fee_bps: number;
+  redeem_fee_bps: number;
+  version: string | null;
+  creator: string;
+  balance: string;
+  latest_market: string | null;
+  roll_pending: boolean;
+  active: boolean;
+}
+
+export interface CodeRelease {
+  version: string;
+  market_code_hash: string;
+  claim_token_code_hash: string;
+  release_notes: string;
+  published_at: string;
+}
+
+export interface DeploymentFailure {
+  market_key: string;
+  market_id: string;
+  creator: string;
+  failed_steps: string[];
+  refunded: string;
+  failed_at: string;
 }
 
 export interface MarketState {
@@ -43,6 +157,52 @@ export interface MarketState {
   short_token_supply: string;
   paused_mint: boolean;
   paused_settle: boolean;
+  insurance_received: string;
+  is_archived: boolean;
+}
+
+export interface AccountPosition {
+  account_id: string;
+  minted_collateral: string;
+  fees_paid: string;
+  redeemed_payouts: string;
+  realized_pnl: string;
+  cost_basis: string;
+  long_balance: string;
+  short_balance: string;
+  settlement_factor: string | null;
+  mark_value: string | null;
+  unrealized_pnl: string | null;
+}
+
+export interface MintQuote {
+  amount: string;
+  storage_deposit: string; // yoctoNEAR to attach to createPosition
+  mint_fee: string;
+  tokens_out: string;
+}
+
+export interface RedeemQuote {
+  settlement_factor: string;
+  gross_payout: string;
+  settle_fee: string;
+  redeem_fee: string;
+  net_payout: string;
+}
+
+export interface PayoffPoint {
+  price: string;
+  settlement_factor: string;
+  long_payout: string;
+  short_payout: string;
+}
+
+export interface SupplyReport {
+  long_counter: string;
+  long_token_supply: string | null;
+  short_counter: string;
+  short_token_supply: string | null;
+  in_sync: boolean;
 }
 
 export interface PriceData {
@@ -76,28 +236,107 @@ export class ForwardFactoryClient {
           'get_markets_by_creator',
           'get_all_markets',
           'get_market_count',
+          'get_deployment_failures',
+          'get_deployment_failure_count',
+          'get_release',
+          'get_releases',
+          'get_default_release',
+          'get_market_code_version',
+          'get_config',
+          'get_outdated_markets',
+          'get_markets_by_underlying',
+          'get_markets_by_quote',
+          'get_markets_by_maturity_bucket',
+          'get_maturity_bucket',
+          'get_markets_by_status',
+          'get_market_status',
+          'get_series',
+          'get_all_series',
+          'get_series_markets',
+          'get_front_month',
+          'is_series_roll_due',
+          'get_creator_bond',
+          'get_approved_quotes',
+          'get_approved_underlyings',
+          'get_param_limits',
+          'check_market_params',
+          'get_position_token',
+          'get_default_token_backend',
         ],
         changeMethods: [
           'deploy_market',
+          'deploy_market_relative',
           'set_paused',
           'update_oracle',
           'update_fee_collector',
           'update_guardian',
+          'propagate_config',
+          'fund_series',
+          'roll_series',
+          'claim_creator_bond',
         ],
       }
     );
   }
 
-  async deployMarket(params: MarketParams): Promise<void> {
-    const deposit = utils.format.parseNearAmount('5'); // 5 NEAR for deployment
+  async deployMarket(params: MarketParams, version?: string, tokenBackend?: TokenBackend): Promise<void> {
+    // market + two token accounts; a Nep245 market needs the market account only
+    const deposit = utils.format.parseNearAmount(tokenBackend === 'Nep245' ? '10' : '20');
     
     await this.contract.deploy_market({
-      args: { params },
+      args: { params, version, token_backend: tokenBackend },
+      gas: new BN('300000000000000'),
+      amount: deposit,
+    });
+  }
+
+  async deployMarketRelative(
+    params: RelativeMarketParams,
+    version?: string,
+    tokenBackend?: TokenBackend
+  ): Promise<boolean> {
+    // refunded if the oracle refuses
+    const deposit = utils.format.parseNearAmount(tokenBackend === 'Nep245' ? '10' : '20');
+    
+    return await this.contract.deploy_market_relative({
+      args: { params, version, token_backend: tokenBackend },
       gas: new BN('300000000000000'),
       amount: deposit,
     });
   }
 
+  async getApprovedQuotes(): Promise<string[]> {
+    return await this.contract.get_approved_quotes();
+  }
+
+  async getApprovedUnderlyings(fromIndex = 0, limit = 50): Promise<UnderlyingListing[]> {
+    return await this.contract.get_approved_underlyings({ from_index: fromIndex, limit });
+  }
+
+  async getParamLimits(): Promise<ParamLimits> {
+    return await this.contract.get_param_limits();
+  }
+
+  async checkMarketParams(params: MarketParams): Promise<string | null> {
+    return await this.contract.check_market_params({ params });
+  }
+
+  async getPositionToken(): Promise<string | null> {
+    return await this.contract.get_position_token();
+  }
+
+  async getDefaultTokenBackend(): Promise<TokenBackend> {
+    return await this.contract.get_default_token_backend();
+  }
+
+  async getCreatorBond(): Promise<string> {
+    return await this.contract.get_creator_bond();
+  }
+
+  async claimCreatorBond(marketKey: string): Promise<void> {
+    await this.contract.claim_creator_bond({ args: { market_key: marketKey } });
+  }
+
   async getMarket(marketKey: string): Promise<MarketInfo | null> {
     return await this.contract.get_market({ market_key: marketKey });
   }
@@ -117,10 +356,83 @@ export class ForwardFactoryClient {
     });
   }
 
+  async getMarketsByUnderlying(underlying: string, cursor?: string, limit = 50): Promise<MarketPage> {
+    return await this.contract.get_markets_by_underlying({ underlying, cursor, limit });
+  }
+
+  async getMarketsByQuote(quote: string, cursor?: string, limit = 50): Promise<MarketPage> {
+    return await this.contract.get_markets_by_quote({ quote, cursor, limit });
+  }
+
+  async getMarketsByMaturityBucket(bucket: number, cursor?: string, limit = 50): Promise<MarketPage> {
+    return await this.contract.get_markets_by_maturity_bucket({ bucket, cursor, limit });
+  }
+
+  async getMarketsByStatus(status: MarketStatus, cursor?: string, limit = 50): Promise<MarketPage> {
+    return await this.contract.get_markets_by_status({ status, cursor, limit });
+  }
+
+  async getMarketStatus(marketKey: string): Promise<MarketStatus | null> {
+    return await this.contract.get_market_status({ market_key: marketKey });
+  }
+
+  async getSeries(seriesId: string): Promise<MarketSeries | null> {
+    return await this.contract.get_series({ series_id: seriesId });
+  }
+
+  async getFrontMonth(seriesId: string): Promise<MarketInfo | null> {
+    return await this.contract.get_front_month({ series_id: seriesId });
+  }
+
+  async getSeriesMarkets(seriesId: string): Promise<MarketInfo[]> {
+    return await this.contract.get_series_markets({ series_id: seriesId });
+  }
+
+  async isSeriesRollDue(seriesId: string): Promise<boolean> {
+    return await this.contract.is_series_roll_due({ series_id: seriesId });
+  }
+
+  async fundSeries(seriesId: string, amount: string): Promise<string> {
+    return await this.contract.fund_series({
+      args: { series_id: seriesId },
+      amount: utils.format.parseNearAmount(amount),
+    });
+  }
+
+  async rollSeries(seriesId: string): Promise<void> {
+    await this.contract.roll_series({
+      args: { series_id: seriesId },
+      gas: new BN('300000000000000'),
+    });
+  }
+
   async getMarketCount(): Promise<number> {
     const count = await this.contract.get_market_count();
     return parseInt(count);
   }
+
+  async getReleases(fromIndex = 0, limit = 100): Promise<CodeRelease[]> {
+    return await this.contract.get_releases({ from_index: fromIndex, limit });
+  }
+
+  async getDefaultRelease(): Promise<CodeRelease | null> {
+    return await this.contract.get_default_release();
+  }
+
+  async getMarketCodeVersion(marketKey: string): Promise<string | null> {
+    return await this.contract.get_market_code_version({ market_key: marketKey });
+  }
+
+  async getOutdatedMarkets(fromIndex = 0, limit = 100): Promise<MarketInfo[]> {
+    return await this.contract.get_outdated_markets({ from_index: fromIndex, limit });
+  }
+
+  async getDeploymentFailures(fromIndex = 0, limit = 100): Promise<DeploymentFailure[]> {
+    return await this.contract.get_deployment_failures({
+      from_index: fromIndex,
+      limit,
+    });
+  }
 }
 
 // Forward Market Client
@@ -146,22 +458,49 @@ export class ForwardMarketClient {
           'get_market_params',
           'get_market_state',
           'get_user_deposit',
+          'get_collateral_shortfall',
           'preview_settlement',
+          'preview_mint',
+          'preview_redeem',
+          'preview_payoff_curve',
+          'get_caps',
+          'get_mint_capacity',
+          'get_claim_deadline',
+          'get_token_backend',
+          'get_mint_storage_deposit',
+          'get_account_position',
         ],
         changeMethods: [
           'create_position',
+          'cancel_mint',
           'redeem',
           'settle',
+          'archive',
+          'request_insurance_draw',
           'set_paused',
+          'check_supply',
+          'reconcile_supply',
+          'reset_supply_ops_in_flight',
         ],
       }
     );
   }
 
-  async createPosition(amount: string): Promise<void> {
+  async createPosition(amount: string, referrer?: string): Promise<void> {
+    // Only a first mint needs storage NEAR; the tokens return any excess
+    const quote = await this.previewMint(amount, this.wallet.getAccountId());
+    
     await this.contract.create_position({
-      args: { amount },
+      args: { amount, referrer },
       gas: new BN('100000000000000'),
+      amount: quote.storage_deposit,
+    });
+  }
+
+  async cancelMint(actionId: string): Promise<void> {
+    await this.contract.cancel_mint({
+      args: { action_id: actionId },
+      gas: new BN('30000000000000'),
     });
   }
 
@@ -171,7 +510,7 @@ export class ForwardMarketClient {
         long_amount: longAmount,
         short_amount: shortAmount,
       },
-      gas: new BN('100000000000000'),
+      gas: new BN('150000000000000'),
     });
   }
 
@@ -181,6 +520,35 @@ export class ForwardMarketClient {
     });
   }
 
+  async archive(): Promise<void> {
+    await this.contract.archive({
+      gas: new BN('300000000000000'),
+    });
+  }
+
+  // Cross-checks the market's supply counters against both tokens
+  async checkSupply(): Promise<SupplyReport> {
+    return await this.contract.check_supply({
+      gas: new BN('50000000000000'),
+    });
+  }
+
+  async getCaps(): Promise<MarketCaps> {
+    return await this.contract.get_caps();
+  }
+
+  async getMintCapacity(accountId: string): Promise<string | null> {
+    return await this.contract.get_mint_capacity({ account_id: accountId });
+  }
+
+  async getMintStorageDeposit(): Promise<string> {
+    return await this.contract.get_mint_storage_deposit();
+  }
+
+  async getClaimDeadline(): Promise<number> {
+    return await this.contract.get_claim_deadline();
+  }
+
   async getMarketParams(): Promise<MarketParams> {
     return await this.contract.get_market_params();
   }
@@ -189,15 +557,67 @@ export class ForwardMarketClient {
     return await this.contract.get_market_state();
   }
 
+  async getCollateralShortfall(): Promise<string> {
+    return await this.contract.get_collateral_shortfall();
+  }
+
+  async requestInsuranceDraw(): Promise<void> {
+    await this.contract.request_insurance_draw({
+      gas: new BN('50000000000000'),
+    });
+  }
+
   async getUserDeposit(account: string): Promise<string> {
     return await this.contract.get_user_deposit({ account });
   }
 
-  async previewSettlement(hypotheticalPrice: string): Promise<[string, string]> {
+  // Ledger plus the given LONG/SHORT balances, marked at the settlement factor or a hypothetical price
+  async getAccountPosition(
+    accountId: string,
+    longBalance: string,
+    shortBalance: string,
+    hypotheticalPrice?: string
+  ): Promise<AccountPosition> {
+    return await this.contract.get_account_position({
+      account_id: accountId,
+      long_balance: longBalance,
+      short_balance: shortBalance,
+      hypothetical_price: hypotheticalPrice,
+    });
+  }
+
+  async previewSettlement(hypotheticalPrice: string, amount?: string): Promise<[string, string]> {
     return await this.contract.preview_settlement({
       hypothetical_price: hypotheticalPrice,
+      amount,
+    });
+  }
+
+  async previewMint(amount: string, accountId?: string, discountBps?: number): Promise<MintQuote> {
+    return await this.contract.preview_mint({
+      amount,
+      account_id: accountId,
+      discount_bps: discountBps,
+    });
+  }
+
+  async previewRedeem(
+    longAmount: string,
+    shortAmount: string,
+    hypotheticalPrice?: string,
+    discountBps?: number
+  ): Promise<RedeemQuote> {
+    return await this.contract.preview_redeem({
+      long_amount: longAmount,
+      short_amount: shortAmount,
+      hypothetical_price: hypotheticalPrice,
+      discount_bps: discountBps,
     });
   }
+
+  async previewPayoffCurve(amount: string, prices: string[]): Promise<PayoffPoint[]> {
+    return await this.contract.preview_payoff_curve({ amount, prices });
+  }
 }
 
 // NEP-141 Token Client (for LONG/SHORT tokens)
@@ -223,11 +643,28 @@ export class NEP141TokenClient {
           'ft_balance_of',
           'ft_total_supply',
           'ft_metadata',
+          'claim_metadata',
+          'is_settled',
+          'get_current_snapshot_id',
+          'get_snapshot_timestamp',
+          'balance_of_at',
+          'total_supply_at',
+          'ft_allowance',
+          'get_allowance',
+          'get_permit_nonce',
+          'get_holders',
+          'get_holder_count',
         ],
         changeMethods: [
           'ft_transfer',
           'ft_transfer_call',
           'storage_deposit',
+          'ft_approve',
+          'ft_revoke',
+          'ft_transfer_from',
+          'register_permit_key',
+          'permit',
+          'register_holders',
         ],
       }
     );
@@ -241,6 +678,109 @@ export class NEP141TokenClient {
     return await this.contract.ft_total_supply();
   }
 
+  async getClaimMetadata(): Promise<ClaimMetadata> {
+    return await this.contract.claim_metadata();
+  }
+
+  async isSettled(): Promise<boolean> {
+    return await this.contract.is_settled();
+  }
+
+  async getCurrentSnapshotId(): Promise<number> {
+    return await this.contract.get_current_snapshot_id();
+  }
+
+  async getBalanceAt(accountId: string, snapshotId: number): Promise<string> {
+    return await this.contract.balance_of_at({ account_id: accountId, snapshot_id: snapshotId });
+  }
+
+  async getTotalSupplyAt(snapshotId: number): Promise<string> {
+    return await this.contract.total_supply_at({ snapshot_id: snapshotId });
+  }
+
+  async getHolders(fromIndex = 0, limit = 100): Promise<[string, string][]> {
+    return await this.contract.get_holders({ from_index: fromIndex, limit });
+  }
+
+  async getHolderCount(): Promise<number> {
+    return await this.contract.get_holder_count();
+  }
+
+  async getAllowance(ownerId: string, spenderId: string): Promise<string> {
+    return await this.contract.ft_allowance({ owner_id: ownerId, spender_id: spenderId });
+  }
+
+  async approve(spenderId: string, amount: string, expiresAt?: number): Promise<void> {
+    const deposit = utils.format.parseNearAmount('0.002'); // allowance storage, excess refunded
+    
+    await this.contract.ft_approve({
+      args: { spender_id: spenderId, amount, expires_at: expiresAt },
+      amount: deposit,
+    });
+  }
+
+  async revoke(spenderId: string): Promise<void> {
+    await this.contract.ft_revoke({
+      args: { spender_id: spenderId },
+      amount: '1',
+    });
+  }
+
+  async transferFrom(ownerId: string, receiverId: string, amount: string, memo?: string): Promise<void> {
+    await this.contract.ft_transfer_from({
+      args: {
+        owner_id: ownerId,
+        receiver_id: receiverId,
+        amount,
+        memo,
+      },
+      gas: new BN('30000000000000'),
+      amount: '1',
+    });
+  }
+
+  async getPermitNonce(ownerId: string): Promise<number> {
+    return await this.contract.get_permit_nonce({ owner_id: ownerId });
+  }
+
+  // `signature` is the base64 ed25519 signature, by the owner's registered permit key, of
+  // "<token>:<owner>:<spender>:<amount>:<expires_at or 0>:<nonce>"
+  async permit(
+    ownerId: string,
+    spenderId: string,
+    amount: string,
+    nonce: number,
+    signature: string,
+    expiresAt?: number
+  ): Promise<void> {
+    const deposit = utils.format.parseNearAmount('0.002');
+    
+    await this.contract.permit({
+      args: {
+        owner_id: ownerId,
+        spender_id: spenderId,
+        amount,
+        expires_at: expiresAt,
+        nonce,
+        signature,
+      },
+      amount: deposit,
+    });
+  }
+
+  // After settlement this redeems the tokens through the market
+  async redeemViaMarket(marketId: string, amount: string): Promise<void> {
+    await this.contract.ft_transfer_call({
+      args: {
+        receiver_id: marketId,
+        amount,
+        msg: 'redeem',
+      },
+      gas: new BN('150000000000000'),
+      amount: '1',
+    });
+  }
+
   async getMetadata(): Promise<any> {
     return await this.contract.ft_metadata();
   }
@@ -269,6 +809,84 @@ export class NEP141TokenClient {
   }
 }
 
+// NEP-245 Position Token Client (token ids are `<market>:long` / `<market>:short`)
+export class PositionTokenClient {
+  private connection: any;
+  private wallet: WalletConnection;
+  private contract: any;
+
+  constructor(
+    private positionTokenAccountId: string,
+    private nearConfig = config
+  ) {}
+
+  async init() {
+    this.connection = await connect(this.nearConfig);
+    this.wallet = new WalletConnection(this.connection, 'forward-markets');
+    
+    this.contract = new Contract(
+      this.wallet.account(),
+      this.positionTokenAccountId,
+      {
+        viewMethods: [
+          'mt_balance_of',
+          'mt_batch_balance_of',
+          'mt_supply',
+          'mt_token',
+          'get_position_token',
+          'get_market_token_ids',
+          'storage_balance_of',
+          'storage_balance_bounds',
+        ],
+        changeMethods: [
+          'mt_transfer',
+          'mt_batch_transfer',
+          'mt_transfer_call',
+          'storage_deposit',
+          'storage_withdraw',
+        ],
+      }
+    );
+  }
+
+  async getMarketTokenIds(market: string): Promise<string[]> {
+    return await this.contract.get_market_token_ids({ market });
+  }
+
+  async getBalances(accountId: string, tokenIds: string[]): Promise<string[]> {
+    return await this.contract.mt_batch_balance_of({ account_id: accountId, token_ids: tokenIds });
+  }
+
+  async getSupply(tokenId: string): Promise<string | null> {
+    return await this.contract.mt_supply({ token_id: tokenId });
+  }
+
+  async transfer(receiverId: string, tokenId: string, amount: string, memo?: string): Promise<void> {
+    await this.contract.mt_transfer({
+      args: {
+        receiver_id: receiverId,
+        token_id: tokenId,
+        amount,
+        memo,
+      },
+      gas: new BN('30000000000000'),
+      amount: '1', // 1 yoctoNEAR for security
+    });
+  }
+
+  // The receiver of a transfer needs a storage deposit for each new token id it holds
+  async storageDeposit(accountId?: string): Promise<void> {
+    const bounds = await this.contract.storage_balance_bounds();
+    
+    await this.contract.storage_deposit({
+      args: {
+        account_id: accountId,
+      },
+      amount: bounds.min,
+    });
+  }
+}
+
 // Oracle Router Client (Updated for Rhea Finance)
 export class OracleRouterClient {
   private connection: any;
@@ -399,6 +1017,9 @@ export async function example() {
   console.log('LONG balance:', longBalance);
   console.log('SHORT balance:', shortBalance);
 
+  const position = await marketClient.getAccountPosition(accountId, longBalance, shortBalance);
+  console.log('Position:', position);
+
   // Preview settlement at different prices
   const [longValue1, shortValue1] = await marketClient.previewSettlement('40000000000000000000000000');
   console.log('At price 40: LONG =', longValue1, 'SHORT =', shortValue1);
@@ -0,0 +1,745 @@
+use near_contract_standards::fungible_token::metadata::{
+    FungibleTokenMetadata, FungibleTokenMetadataProvider, FT_METADATA_SPEC,
+};
+use near_contract_standards::fungible_token::core::FungibleTokenCore;
+use near_contract_standards::fungible_token::resolver::FungibleTokenResolver;
+use near_contract_standards::fungible_token::FungibleToken;
+use near_contract_standards::storage_management::{StorageBalance, StorageBalanceBounds, StorageManagement};
+use near_sdk::borsh::{BorshDeserialize, BorshSerialize};
+use near_sdk::collections::{LookupMap, UnorderedSet, Vector};
+use near_sdk::json_types::{Base64VecU8, U128};
+use near_sdk::serde::{Deserialize, Serialize};
+use near_sdk::serde_json::json;
+use near_sdk::{
+    assert_one_yocto, env, near, require, AccountId, CurveType, Gas, NearToken, PanicOnDefault, Promise,
+    PromiseOrValue, PublicKey,
+};
+
+type Balance = u128;
+
+const MIGRATE_GAS: Gas = Gas::from_tgas(20);
+const FACTOR_SCALE: u128 = 1_000_000_000_000_000_000_000_000;
+const STATE_VERSION: u16 = 1;
+// Allowances are not part of NEP-141, so their events use a standard of our own.
+const ALLOWANCE_EVENT_STANDARD: &str = "claim-token-allowance";
+const STATE_VERSION_KEY: &[u8] = b"STATE_VERSION";
+
+#[derive(BorshSerialize, BorshDeserialize, Serialize, Deserialize, Clone, Copy, PartialEq)]
+#[serde(crate = "near_sdk::serde")]
+pub enum Side {
+    Long,
+    Short,
+}
+
+#[derive(BorshSerialize, BorshDeserialize, Serialize, Deserialize, Clone)]
+#[serde(crate = "near_sdk::serde")]
+pub struct ClaimTerms {
+    pub maturity: u64,
+    pub strike_k: U128,
+    pub lower_bound_l: U128,
+    pub upper_bound_u: U128,
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(crate = "near_sdk::serde")]
+pub struct ClaimMetadata {
+    pub side: Side,
+    pub market: AccountId,
+    pub terms: Option<ClaimTerms>,
+    pub name: String,
+    pub symbol: String,
+    pub icon: Option<String>,
+    pub reference: Option<String>,
+    pub decimals: u8,
+    pub settlement_factor: Option<U128>,
+    pub payout_per_token: Option<U128>,
+    pub restrict_transfers: bool,
+}
+
+#[derive(BorshSerialize, BorshDeserialize, Serialize, Deserialize, Clone)]
+#[serde(crate = "near_sdk::serde")]
+pub struct Allowance {
+    pub amount: U128,
+    pub expires_at: Option<u64>,
+}
+
+#[near(contract_state)]
+#[derive(PanicOnDefault)]
+pub struct ClaimToken {
+    token: FungibleToken,
+    metadata: FungibleTokenMetadata,
+    market: AccountId,
+    side: Side,
+    terms: Option<ClaimTerms>,
+    settlement_factor: Option<U128>,
+    restrict_transfers: bool,
+    // Timestamp of each snapshot; snapshot ids start at 1.
+    snapshots: Vector<u64>,
+    // Balances as they stood at a snapshot, written on the first change after it.
+    balance_checkpoints: LookupMap<AccountId, Vec<(u64, Balance)>>,
+    supply_checkpoints: Vector<(u64, Balance)>,
+    // Keyed by "owner|spender".
+    allowances: LookupMap<String, Allowance>,
+    permit_keys: LookupMap<AccountId, PublicKey>,
+    permit_nonces: LookupMap<AccountId, u64>,
+    // Accounts with a positive balance. Accounts that have not moved since
+    // this was introduced are added through `register_holders`.
+    holders: UnorderedSet<AccountId>,
+    // Who paid for an allowance's storage, when not its owner (a permit relayer).
+    allowance_payers: LookupMap<String, AccountId>,
+}
+
+// Layout shared by the retired long-token and short-token contracts.
+#[derive(BorshSerialize, BorshDeserialize)]
+pub struct LegacyClaimToken {
+    token: FungibleToken,
+    metadata: FungibleTokenMetadata,
+    market: AccountId,
+}
+
+impl From<LegacyClaimToken> for ClaimToken {
+    fn from(old: LegacyClaimToken) -> Self {
+        let side = if old.metadata.symbol.starts_with("SHORT") {
+            Side::Short
+        } else {
+            Side::Long
+        };
+        Self {
+            token: old.token,
+            metadata: old.metadata,
+            market: old.market,
+            side,
+            terms: None,
+            settlement_factor: None,
+            restrict_transfers: true,
+            snapshots: Vector::new(b"n"),
+            balance_checkpoints: LookupMap::new(b"c"),
+            supply_checkpoints: Vector::new(b"p"),
+            allowances: LookupMap::new(b"a"),
+            permit_keys: LookupMap::new(b"k"),
+            permit_nonces: LookupMap::new(b"o"),
+            holders: UnorderedSet::new(b"h"),
+            allowance_payers: LookupMap::new(b"y"),
+        }
+    }
+}
+
+#[near]
+impl ClaimToken {
+    #[init]
+    pub fn new(
+        market: AccountId,
+        side: Side,
+        name: String,
+        symbol: String,
+        decimals: u8,
+        terms: ClaimTerms,
+        icon: Option<String>,
+        reference: Option<String>,
+        reference_hash: Option<Base64VecU8>,
+    ) -> Self {
+        assert!(!env::state_exists(), "Already initialized");
+        Self::write_state_version();
+
+        let metadata = FungibleTokenMetadata {
+            spec: FT_METADATA_SPEC.to_string(),
+            name,
+            symbol,
+            icon,
+            reference,
+            reference_hash,
+            decimals,
+        };
+        metadata.assert_valid();
+
+        let mut this = Self {
+            token: FungibleToken::new(b"t".to_vec()),
+            metadata,
+            market: market.clone(),
+            side,
+            terms: Some(terms),
+            settlement_factor: None,
+            restrict_transfers: true,
+            snapshots: Vector::new(b"n"),
+            balance_checkpoints: LookupMap::new(b"c"),
+            supply_checkpoints: Vector::new(b"p"),
+            allowances: LookupMap::new(b"a"),
+            permit_keys: LookupMap::new(b"k"),
+            permit_nonces: LookupMap::new(b"o"),
+            holders: UnorderedSet::new(b"h"),
+            allowance_payers: LookupMap::new(b"y"),
+        };
+
+        this.token.account_storage_usage += this.holder_storage_usage();
+        this.token.internal_register_account(&market);
+        this
+    }
+
+    /// The market forwards the NEAR its minter attached for storage. A new
+    /// account is registered from it and any excess goes back to the minter.
+    #[payable]
+    pub fn mint(&mut self, account_id: AccountId, amount: U128) {
+        self.assert_market();
+        let mut refund = env::attached_deposit().as_yoctonear();
+        if !self.token.accounts.contains_key(&account_id) {
+            let registration = self.token.account_storage_usage as Balance * env::storage_byte_cost().as_yoctonear();
+            require!(refund >= registration, "Attached deposit does not cover registration");
+            self.token.internal_register_account(&account_id);
+            refund -= registration;
+            env::log_str(&format!("Registered {} on first mint", account_id));
+        }
+        if refund > 0 {
+            Promise::new(account_id.clone()).transfer(NearToken::from_yoctonear(refund));
+        }
+        self.record_checkpoint(&account_id);
+        self.record_supply_checkpoint();
+        self.token.internal_deposit(&account_id, amount.into());
+        self.sync_holder(&account_id);
+    }
+
+    pub fn burn(&mut self, account_id: AccountId, amount: U128) {
+        self.assert_market();
+        self.record_checkpoint(&account_id);
+        self.record_supply_checkpoint();
+        self.token.internal_withdraw(&account_id, amount.into());
+        self.sync_holder(&account_id);
+    }
+
+    pub fn snapshot(&mut self) -> u64 {
+        let caller = env::predecessor_account_id();
+        let account_id = env::current_account_id();
+        let factory = account_id.get_parent_account_id().expect("No factory account");
+        require!(caller == self.market || caller == factory, "Only market or factory can snapshot");
+        self.snapshots.push(&env::block_timestamp());
+        let snapshot_id = self.snapshots.len();
+        env::log_str(&format!("Snapshot {} taken", snapshot_id));
+        snapshot_id
+    }
+
+    pub fn mark_settled(&mut self, settlement_factor: U128) {
+        self.assert_market();
+        require!(self.settlement_factor.is_none(), "Already settled");
+        require!(settlement_factor.0 <= FACTOR_SCALE, "Invalid settlement factor");
+        self.settlement_factor = Some(settlement_factor);
+        env::log_str(&format!(
+            "Token {} settled, payout per token {}",
+            env::current_account_id(),
+            self.payout_per_token().unwrap_or(0)
+        ));
+    }
+
+    pub fn set_transfer_restriction(&mut self, restricted: bool) {
+        self.assert_market();
+        self.restrict_transfers = restricted;
+        env::log_str(&format!("Settled transfer restriction: {}", restricted));
+    }
+
+    // The approver pays for the allowance's storage; any excess is refunded.
+    #[payable]
+    pub fn ft_approve(&mut self, spender_id: AccountId, amount: U128, expires_at: Option<u64>) {
+        require!(env::attached_deposit().as_yoctonear() > 0, "Requires attached deposit of at least 1 yoctoNEAR");
+        let owner_id = env::predecessor_account_id();
+        self.internal_approve(&owner_id, &spender_id, amount, expires_at, &owner_id);
+    }
+
+    // The storage goes back to whoever paid for the allowance.
+    #[payable]
+    pub fn ft_revoke(&mut self, spender_id: AccountId) {
+        assert_one_yocto();
+        let owner_id = env::predecessor_account_id();
+        let key = Self::allowance_key(&owner_id, &spender_id);
+        let initial_storage = env::storage_usage();
+        require!(self.allowances.remove(&key).is_some(), "No allowance to revoke");
+        let payer = self.allowance_payers.remove(&key).unwrap_or_else(|| owner_id.clone());
+        let released = initial_storage.saturating_sub(env::storage_usage()) as Balance * env::storage_byte_cost().as_yoctonear();
+        if released > 0 {
+            Promise::new(payer).transfer(NearToken::from_yoctonear(released));
+        }
+        Self::emit_event("ft_revoke", json!([{ "owner_id": owner_id, "spender_id": spender_id }]));
+    }
+
+    #[payable]
+    pub fn ft_transfer_from(
+        &mut self,
+        owner_id: AccountId,
+        receiver_id: AccountId,
+        amount: U128,
+        memo: Option<String>,
+    ) {
+        assert_one_yocto();
+        require!(amount.0 > 0, "Amount must be positive");
+        let spender_id = env::predecessor_account_id();
+        let key = Self::allowance_key(&owner_id, &spender_id);
+        let mut allowance = self.allowances.get(&key).expect("No allowance");
+        require!(
+            allowance.expires_at.map_or(true, |expires_at| env::block_timestamp() < expires_at),
+            "Allowance expired"
+        );
+        require!(allowance.amount.0 >= amount.0, "Allowance exceeded");
+        allowance.amount = U128(allowance.amount.0 - amount.0);
+        self.allowances.insert(&key, &allowance);
+        
+        self.assert_transferable(&receiver_id);
+        self.record_checkpoint(&owner_id);
+        self.record_checkpoint(&receiver_id);
+        self.token.internal_transfer(&owner_id, &receiver_id, amount.0, memo);
+        self.sync_holder(&owner_id);
+        self.sync_holder(&receiver_id);
+    }
+
+    // Accounts registered before holder entries were part of the storage
+    // deposit are listed at the caller's expense; any excess is refunded.
+    #[payable]
+    pub fn register_holders(&mut self, account_ids: Vec<AccountId>) -> u64 {
+        let before = self.holders.len();
+        let initial_storage = env::storage_usage();
+        for account_id in account_ids {
+            self.sync_holder(&account_id);
+        }
+        Self::settle_storage(initial_storage, &env::predecessor_account_id());
+        self.holders.len().saturating_sub(before)
+    }
+
+    // Permits are signed with a key the owner registered here; contracts
+    // cannot read another account's access keys.
+    #[payable]
+    pub fn register_permit_key(&mut self, public_key: PublicKey) {
+        require!(env::attached_deposit().as_yoctonear() > 0, "Requires attached deposit of at least 1 yoctoNEAR");
+        require!(public_key.curve_type() == CurveType::ED25519, "Only ed25519 keys are supported");
+        let owner_id = env::predecessor_account_id();
+        let initial_storage = env::storage_usage();
+        self.permit_keys.insert(&owner_id, &public_key);
+        Self::settle_storage(initial_storage, &owner_id);
+        env::log_str(&format!("Permit key registered for {}", owner_id));
+    }
+
+    // Signed message: "<token>:<owner>:<spender>:<amount>:<expires_at or 0>:<nonce>".
+    // The caller pays the allowance storage.
+    #[payable]
+    pub fn permit(
+        &mut self,
+        owner_id: AccountId,
+        spender_id: AccountId,
+        amount: U128,
+        expires_at: Option<u64>,
+        nonce: u64,
+        signature: Base64VecU8,
+    ) {
+        require!(env::attached_deposit().as_yoctonear() > 0, "Requires attached deposit of at least 1 yoctoNEAR");
+        let public_key = self.permit_keys.get(&owner_id).expect("No permit key registered");
+        let expected_nonce = self.permit_nonces.get(&owner_id).unwrap_or(0);
+        require!(nonce == expected_nonce, "Invalid permit nonce");
+        
+        let message = format!(
+            "{}:{}:{}:{}:{}:{}",
+            env::current_account_id(),
+            owner_id,
+            spender_id,
+            amount.0,
+            expires_at.unwrap_or(0),
+            nonce
+        );
+        let signature: [u8; 64] = signature.0.try_into().expect("Invalid signature length");
+        let key_bytes: [u8; 32] = public_key.as_bytes()[1..].try_into().expect("Invalid public key");
+        require!(
+            env::ed25519_verify(&signature, message.as_bytes(), &key_bytes),
+            "Invalid permit signature"
+        );
+        
+        self.permit_nonces.insert(&owner_id, &(nonce + 1));
+        self.internal_approve(&owner_id, &spender_id, amount, expires_at, &env::predecessor_account_id());
+    }
+
+    pub fn upgrade(&mut self) -> Promise {
+        self.assert_factory();
+        let code = env::input().expect("No code provided");
+        env::log_str(&format!("Token {} upgrading", env::current_account_id()));
+        Promise::new(env::current_account_id())
+            .deploy_contract(code)
+            .function_call("migrate".to_string(), Vec::new(), NearToken::from_yoctonear(0), MIGRATE_GAS)
+    }
+
+    #[private]
+    #[init(ignore_state)]
+    pub fn migrate() -> Self {
+        let version = Self::read_state_version();
+        assert!(version <= STATE_VERSION, "State version is newer than code");
+        let mut token: Self = match version {
+            STATE_VERSION => env::state_read().expect("Failed to read state"),
+            _ => {
+                let old: LegacyClaimToken = env::state_read().expect("Failed to read state");
+                old.into()
+            }
+        };
+        // Registration now also pays for the account's holder entry.
+        if version < STATE_VERSION {
+            token.token.account_storage_usage += token.holder_storage_usage();
+        }
+        Self::write_state_version();
+        env::log_str(&format!(
+            "Token state migrated from version {} to {}",
+            version, STATE_VERSION
+        ));
+        token
+    }
+
+    pub fn get_state_version(&self) -> u16 {
+        Self::read_state_version()
+    }
+
+    pub fn teardown(&mut self, beneficiary: AccountId) -> Promise {
+        self.assert_factory();
+        assert_eq!(self.token.total_supply, 0, "Token supply is not zero");
+        env::log_str(&format!("Token {} torn down", env::current_account_id()));
+        Promise::new(env::current_account_id()).delete_account(beneficiary)
+    }
+
+    pub fn archive(&mut self, beneficiary: AccountId) -> Promise {
+        self.assert_factory();
+        env::log_str(&format!(
+            "Token {} archived with {} unredeemed",
+            env::current_account_id(),
+            self.token.total_supply
+        ));
+        Promise::new(env::current_account_id()).delete_account(beneficiary)
+    }
+
+    pub fn get_side(&self) -> Side {
+        self.side
+    }
+
+    pub fn get_market(&self) -> AccountId {
+        self.market.clone()
+    }
+
+    pub fn claim_metadata(&self) -> ClaimMetadata {
+        ClaimMetadata {
+            side: self.side,
+            market: self.market.clone(),
+            terms: self.terms.clone(),
+            name: self.metadata.name.clone(),
+            symbol: self.metadata.symbol.clone(),
+            icon: self.metadata.icon.clone(),
+            reference: self.metadata.reference.clone(),
+            decimals: self.metadata.decimals,
+            settlement_factor: self.settlement_factor,
+            payout_per_token: self.payout_per_token().map(U128),
+            restrict_transfers: self.restrict_transfers,
+        }
+    }
+
+    pub fn is_settled(&self) -> bool {
+        self.settlement_factor.is_some()
+    }
+
+    pub fn get_current_snapshot_id(&self) -> u64 {
+        self.snapshots.len()
+    }
+
+    pub fn get_snapshot_timestamp(&self, snapshot_id: u64) -> Option<u64> {
+        snapshot_id.checked_sub(1).and_then(|index| self.snapshots.get(index))
+    }
+
+    pub fn balance_of_at(&self, account_id: AccountId, snapshot_id: u64) -> U128 {
+        self.assert_snapshot_id(snapshot_id);
+        let checkpoints = self.balance_checkpoints.get(&account_id).unwrap_or_default();
+        U128(
+            Self::value_at(&checkpoints, snapshot_id)
+                .unwrap_or_else(|| self.token.accounts.get(&account_id).unwrap_or(0)),
+        )
+    }
+
+    pub fn total_supply_at(&self, snapshot_id: u64) -> U128 {
+        self.assert_snapshot_id(snapshot_id);
+        let checkpoints: Vec<(u64, Balance)> = self.supply_checkpoints.iter().collect();
+        U128(Self::value_at(&checkpoints, snapshot_id).unwrap_or(self.token.total_supply))
+    }
+
+    pub fn ft_allowance(&self, owner_id: AccountId, spender_id: AccountId) -> U128 {
+        self.allowances
+            .get(&Self::allowance_key(&owner_id, &spender_id))
+            .filter(|allowance| allowance.expires_at.map_or(true, |expires_at| env::block_timestamp() < expires_at))
+            .map_or(U128(0), |allowance| allowance.amount)
+    }
+
+    pub fn get_allowance(&self, owner_id: AccountId, spender_id: AccountId) -> Option<Allowance> {
+        self.allowances.get(&Self::allowance_key(&owner_id, &spender_id))
+    }
+
+    pub fn get_permit_key(&self, owner_id: AccountId) -> Option<PublicKey> {
+        self.permit_keys.get(&owner_id)
+    }
+
+    pub fn get_permit_nonce(&self, owner_id: AccountId) -> u64 {
+        self.permit_nonces.get(&owner_id).unwrap_or(0)
+    }
+
+    pub fn get_holders(&self, from_index: u64, limit: u64) -> Vec<(AccountId, U128)> {
+        self.holders
+            .iter()
+            .skip(from_index as usize)
+            .take(limit as usize)
+            .map(|account_id| {
+                let balance = self.token.accounts.get(&account_id).unwrap_or(0);
+                (account_id, U128(balance))
+            })
+            .collect()
+    }
+
+    pub fn get_holder_count(&self) -> u64 {
+        self.holders.len()
+    }
+
+    // Measured with the longest possible account id, like the token's own
+    // per-account storage.
+    fn holder_storage_usage(&mut self) -> u64 {
+        let initial_storage = env::storage_usage();
+        let probe = AccountId::new_unvalidated("a".repeat(64));
+        self.holders.insert(&probe);
+        let usage = env::storage_usage() - initial_storage;
+        self.holders.remove(&probe);
+        usage
+    }
+
+    fn sync_holder(&mut self, account_id: &AccountId) {
+        if self.token.accounts.get(account_id).unwrap_or(0) > 0 {
+            self.holders.insert(account_id);
+        } else {
+            self.holders.remove(account_id);
+        }
+    }
+
+    fn internal_approve(
+        &mut self,
+        owner_id: &AccountId,
+        spender_id: &AccountId,
+        amount: U128,
+        expires_at: Option<u64>,
+        payer: &AccountId,
+    ) {
+        require!(owner_id != spender_id, "Cannot approve self");
+        if let Some(expires_at) = expires_at {
+            require!(expires_at > env::block_timestamp(), "Expiry in the past");
+        }
+        let key = Self::allowance_key(owner_id, spender_id);
+        let initial_storage = env::storage_usage();
+        // Updating an allowance keeps its original payer, who holds the refund.
+        if self.allowances.insert(&key, &Allowance { amount, expires_at }).is_none() && payer != owner_id {
+            self.allowance_payers.insert(&key, payer);
+        }
+        Self::settle_storage(initial_storage, payer);
+        Self::emit_event("ft_approve", json!([{
+            "owner_id": owner_id,
+            "spender_id": spender_id,
+            "amount": amount,
+            "expires_at": expires_at,
+        }]));
+    }
+
+    fn settle_storage(initial_storage: u64, payer: &AccountId) {
+        let cost = env::storage_usage().saturating_sub(initial_storage) as Balance * env::storage_byte_cost().as_yoctonear();
+        let deposit = env::attached_deposit().as_yoctonear();
+        require!(deposit >= cost, &format!("Insufficient deposit for storage, need {}", cost));
+        if deposit > cost {
+            Promise::new(payer.clone()).transfer(NearToken::from_yoctonear(deposit - cost));
+        }
+    }
+
+    fn allowance_key(owner_id: &AccountId, spender_id: &AccountId) -> String {
+        format!("{}|{}", owner_id, spender_id)
+    }
+
+    fn emit_event(event: &str, data: near_sdk::serde_json::Value) {
+        env::log_str(&format!(
+            "EVENT_JSON:{}",
+            json!({
+                "standard": ALLOWANCE_EVENT_STANDARD,
+                "version": "1.0.0",
+                "event": event,
+                "data": data,
+            })
+        ));
+    }
+
+    fn assert_snapshot_id(&self, snapshot_id: u64) {
+        require!(snapshot_id > 0 && snapshot_id <= self.snapshots.len(), "Unknown snapshot");
+    }
+
+    // A checkpoint (id, balance) holds the balance for every snapshot after the
+    // previous checkpoint up to id. Nothing recorded means unchanged since.
+    fn value_at(checkpoints: &[(u64, Balance)], snapshot_id: u64) -> Option<Balance> {
+        let index = checkpoints.partition_point(|(id, _)| *id < snapshot_id);
+        checkpoints.get(index).map(|(_, balance)| *balance)
+    }
+
+    fn record_checkpoint(&mut self, account_id: &AccountId) {
+        let snapshot_id = self.snapshots.len();
+        if snapshot_id == 0 {
+            return;
+        }
+        let mut checkpoints = self.balance_checkpoints.get(account_id).unwrap_or_default();
+        if checkpoints.last().map_or(true, |(id, _)| *id < snapshot_id) {
+            checkpoints.push((snapshot_id, self.token.accounts.get(account_id).unwrap_or(0)));
+            self.balance_checkpoints.insert(account_id, &checkpoints);
+        }
+    }
+
+    fn record_supply_checkpoint(&mut self) {
+        let snapshot_id = self.snapshots.len();
+        if snapshot_id == 0 {
+            return;
+        }
+        let last = self.supply_checkpoints.len().checked_sub(1).and_then(|index| self.supply_checkpoints.get(index));
+        if last.map_or(true, |(id, _)| id < snapshot_id) {
+            self.supply_checkpoints.push(&(snapshot_id, self.token.total_supply));
+        }
+    }
+
+    // Quote units paid for one whole token (10^decimals base units).
+    fn payout_per_token(&self) -> Option<u128> {
+        self.settlement_factor.map(|factor| {
+            let side_factor = match self.side {
+                Side::Long => factor.0,
+                Side::Short => FACTOR_SCALE - factor.0,
+            };
+            // The factor carries 24 decimals; rescale it to the token's.
+            let decimals = self.metadata.decimals as u32;
+            if decimals <= 24 {
+                side_factor / 10_u128.pow(24 - decimals)
+            } else {
+                side_factor * 10_u128.pow(decimals - 24)
+            }
+        })
+    }
+
+    // Once settled a token is only a claim on a fixed payout, so it can only
+    // be sent back to the market for redemption.
+    fn assert_transferable(&self, receiver_id: &AccountId) {
+        if self.settlement_factor.is_some() && self.restrict_transfers {
+            require!(*receiver_id == self.market, "Settled tokens can only be transferred to the market");
+        }
+    }
+
+    fn read_state_version() -> u16 {
+        env::storage_read(STATE_VERSION_KEY)
+            .map(|bytes| u16::from_le_bytes(bytes.try_into().expect("Invalid state version")))
+            .unwrap_or(0)
+    }
+
+    fn write_state_version() {
+        env::storage_write(STATE_VERSION_KEY, &STATE_VERSION.to_le_bytes());
+    }
+
+    fn assert_factory(&self) {
+        let account_id = env::current_account_id();
+        let factory = account_id.get_parent_account_id().expect("No factory account");
+        assert_eq!(
+            env::predecessor_account_id().as_str(),
+            factory.as_str(),
+            "Only factory can call this method"
+        );
+    }
+
+    fn assert_market(&self) {
+        assert_eq!(
+            env::predecessor_account_id(),
+            self.market,
+            "Only market can mint/burn"
+        );
+    }
+}
+
+#[near]
+impl FungibleTokenMetadataProvider for ClaimToken {
+    fn ft_metadata(&self) -> FungibleTokenMetadata {
+        let mut metadata = self.metadata.clone();
+        if let Some(payout) = self.payout_per_token() {
+            metadata.name = format!("{} (settled, pays {})", metadata.name, payout);
+        }
+        metadata
+    }
+}
+
+#[near]
+impl FungibleTokenCore for ClaimToken {
+    #[payable]
+    fn ft_transfer(&mut self, receiver_id: AccountId, amount: U128, memo: Option<String>) {
+        self.assert_transferable(&receiver_id);
+        let sender_id = env::predecessor_account_id();
+        self.record_checkpoint(&sender_id);
+        self.record_checkpoint(&receiver_id);
+        self.token.ft_transfer(receiver_id.clone(), amount, memo);
+        self.sync_holder(&sender_id);
+        self.sync_holder(&receiver_id);
+    }
+
+    #[payable]
+    fn ft_transfer_call(
+        &mut self,
+        receiver_id: AccountId,
+        amount: U128,
+        memo: Option<String>,
+        msg: String,
+    ) -> PromiseOrValue<U128> {
+        self.assert_transferable(&receiver_id);
+        let sender_id = env::predecessor_account_id();
+        self.record_checkpoint(&sender_id);
+        self.record_checkpoint(&receiver_id);
+        let result = self.token.ft_transfer_call(receiver_id.clone(), amount, memo, msg);
+        self.sync_holder(&sender_id);
+        self.sync_holder(&receiver_id);
+        result
+    }
+
+    fn ft_total_supply(&self) -> U128 {
+        self.token.ft_total_supply()
+    }
+
+    fn ft_balance_of(&self, account_id: AccountId) -> U128 {
+        self.token.ft_balance_of(account_id)
+    }
+}
+
+#[near]
+impl FungibleTokenResolver for ClaimToken {
+    #[private]
+    fn ft_resolve_transfer(&mut self, sender_id: AccountId, receiver_id: AccountId, amount: U128) -> U128 {
+        self.record_checkpoint(&sender_id);
+        self.record_checkpoint(&receiver_id);
+        self.record_supply_checkpoint();
+        let (used_amount, burned_amount) =
+            self.token.internal_ft_resolve_transfer(&sender_id, receiver_id.clone(), amount);
+        self.sync_holder(&sender_id);
+        self.sync_holder(&receiver_id);
+        if burned_amount > 0 {
+            env::log_str(&format!("Account @{} burned {}", sender_id, burned_amount));
+        }
+        used_amount.into()
+    }
+}
+
+#[near]
+impl StorageManagement for ClaimToken {
+    #[payable]
+    fn storage_deposit(&mut self, account_id: Option<AccountId>, registration_only: Option<bool>) -> StorageBalance {
+        self.token.storage_deposit(account_id, registration_only)
+    }
+
+    #[payable]
+    fn storage_withdraw(&mut self, amount: Option<NearToken>) -> StorageBalance {
+        self.token.storage_withdraw(amount)
+    }
+
+    #[payable]
+    fn storage_unregister(&mut self, force: Option<bool>) -> bool {
+        self.token.internal_storage_unregister(force).is_some()
+    }
+
+    fn storage_balance_bounds(&self) -> StorageBalanceBounds {
+        self.token.storage_balance_bounds()
+    }
+
+    fn storage_balance_of(&self, account_id: AccountId) -> Option<StorageBalance> {
+        self.token.storage_balance_of(account_id)
+    }
+}
@@ -1,87 +1,737 @@
 use near_contract_standards::fungible_token::receiver::FungibleTokenReceiver;
 use near_sdk::borsh::{BorshDeserialize, BorshSerialize};
-use near_sdk::collections::UnorderedMap;
+use near_sdk::collections::{LookupMap, UnorderedMap, UnorderedSet, Vector};
 use near_sdk::json_types::U128;
-use near_sdk::{env, ext_contract, near, AccountId, Balance, Gas, PanicOnDefault, Promise};
+use near_sdk::serde::{Deserialize, Serialize};
+use near_sdk::{env, ext_contract, near, AccountId, Gas, NearToken, PanicOnDefault, Promise, PromiseOrValue, PromiseResult};
+
+type Balance = u128;
 
 const TGAS: u64 = 1_000_000_000_000;
 const FT_TRANSFER_GAS: Gas = Gas::from_tgas(10);
+const FT_TRANSFER_CALL_GAS: Gas = Gas::from_tgas(50);
+const CALLBACK_GAS: Gas = Gas::from_tgas(10);
+const MAX_BPS: u16 = 10_000;
+const DEFAULT_WITHDRAWAL_TIMELOCK_SECS: u64 = 86_400;
+const MIN_POLICY_CHANGE_DELAY_SECS: u64 = 86_400;
+const DAY_NS: u64 = 86_400 * 1_000_000_000;
+const VOLUME_WINDOW_DAYS: u64 = 30;
+const WITHDRAWAL_WINDOW_DAYS: u64 = 7;
+
+#[derive(BorshSerialize, BorshDeserialize, Serialize, Deserialize, Clone, PartialEq)]
+#[serde(crate = "near_sdk::serde")]
+pub enum DrawStatus {
+    Pending,
+    Approved,
+    Paid,
+    Rejected,
+    Failed,
+}
+
+#[derive(BorshSerialize, BorshDeserialize, Serialize, Deserialize, Clone)]
+#[serde(crate = "near_sdk::serde")]
+pub struct InsuranceDraw {
+    pub draw_id: u64,
+    pub market: AccountId,
+    pub token: AccountId,
+    pub amount: U128,
+    pub paid_amount: U128,
+    pub status: DrawStatus,
+    pub requested_at: u64,
+    pub resolved_at: Option<u64>,
+    pub resolved_by: Option<AccountId>,
+}
+
+#[derive(BorshSerialize, BorshDeserialize, Serialize, Deserialize, Clone, PartialEq)]
+#[serde(crate = "near_sdk::serde")]
+pub enum ProposalStatus {
+    Pending,
+    Queued,
+    Executed,
+    Cancelled,
+}
+
+#[derive(BorshSerialize, BorshDeserialize, Serialize, Deserialize, Clone)]
+#[serde(crate = "near_sdk::serde")]
+pub struct WithdrawalProposal {
+    pub proposal_id: u64,
+    pub token: AccountId,
+    pub amount: U128,
+    pub receiver: AccountId,
+    pub proposer: AccountId,
+    pub approvals: Vec<AccountId>,
+    pub status: ProposalStatus,
+    pub created_at: u64,
+    pub executable_at: Option<u64>,
+    pub resolved_at: Option<u64>,
+}
+
+#[derive(BorshSerialize, BorshDeserialize, Serialize, Deserialize, Clone)]
+#[serde(crate = "near_sdk::serde")]
+pub enum PolicyChangeKind {
+    Policy {
+        approvers: Vec<AccountId>,
+        required_approvals: u32,
+        timelock_secs: u64,
+    },
+    Threshold {
+        token: AccountId,
+        threshold: U128,
+    },
+}
+
+// Loosening the withdrawal rules waits out a delay the guardian can veto in.
+#[derive(BorshSerialize, BorshDeserialize, Serialize, Deserialize, Clone)]
+#[serde(crate = "near_sdk::serde")]
+pub struct PolicyChange {
+    pub change_id: u64,
+    pub change: PolicyChangeKind,
+    pub status: ProposalStatus,
+    pub proposed_at: u64,
+    pub executable_at: u64,
+    pub resolved_at: Option<u64>,
+}
+
+#[derive(BorshSerialize, BorshDeserialize, Serialize, Deserialize, Clone)]
+#[serde(crate = "near_sdk::serde")]
+pub struct FeeTier {
+    pub min_volume: U128,
+    pub discount_bps: u16,
+}
+
+#[derive(BorshSerialize, BorshDeserialize, Clone)]
+pub struct DailyVolume {
+    pub day: u64,
+    pub volume: Balance,
+}
+
+#[derive(BorshSerialize, BorshDeserialize, Serialize, Deserialize, Clone, Default)]
+#[serde(crate = "near_sdk::serde")]
+pub struct ReferrerStats {
+    pub referral_count: u64,
+    pub fees_referred: U128,
+    pub rebates_accrued: U128,
+    pub rebates_claimed: U128,
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(crate = "near_sdk::serde")]
+pub struct WithdrawalPolicy {
+    pub approvers: Vec<AccountId>,
+    pub required_approvals: u32,
+    pub timelock_secs: u64,
+}
+
+// Message a market attaches to a referred mint fee it transfers in.
+#[derive(Serialize, Deserialize)]
+#[serde(crate = "near_sdk::serde")]
+pub struct ReferralFee {
+    pub referrer: AccountId,
+    pub account: AccountId,
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(crate = "near_sdk::serde")]
+pub struct InsuranceConfig {
+    pub guardian: AccountId,
+    pub insurance_share_bps: u16,
+}
 
 #[ext_contract(ext_ft)]
 trait FungibleToken {
     fn ft_transfer(&mut self, receiver_id: AccountId, amount: U128, memo: Option<String>);
+    fn ft_transfer_call(
+        &mut self,
+        receiver_id: AccountId,
+        amount: U128,
+        memo: Option<String>,
+        msg: String,
+    ) -> Promise;
     fn ft_balance_of(&self, account_id: AccountId) -> U128;
 }
 
+#[ext_contract(ext_self)]
+trait SelfCallback {
+    fn on_insurance_draw_paid(&mut self, draw_id: u64) -> bool;
+    fn on_referral_rebate_claimed(&mut self, referrer: AccountId, token: AccountId, amount: U128) -> bool;
+    fn on_creator_fees_claimed(&mut self, creator: AccountId, token: AccountId, amount: U128) -> bool;
+}
+
 #[near(contract_state)]
 #[derive(PanicOnDefault)]
 pub struct FeeCollector {
     owner: AccountId,
     treasury: AccountId,
+    guardian: AccountId,
+    factory: Option<AccountId>,
     authorized_markets: UnorderedMap<AccountId, bool>,
     collected_fees: UnorderedMap<AccountId, Balance>,
+    insurance_share_bps: u16,
+    insurance_balances: UnorderedMap<AccountId, Balance>,
+    insurance_caps: UnorderedMap<AccountId, Balance>,
+    insurance_drawn: UnorderedMap<String, Balance>,
+    insurance_draws: Vector<InsuranceDraw>,
+    approvers: UnorderedSet<AccountId>,
+    required_approvals: u32,
+    withdrawal_timelock_secs: u64,
+    withdrawal_thresholds: UnorderedMap<AccountId, Balance>,
+    direct_withdrawals: LookupMap<AccountId, Vec<DailyVolume>>,
+    withdrawal_proposals: Vector<WithdrawalProposal>,
+    policy_changes: Vector<PolicyChange>,
+    fee_tiers: UnorderedMap<AccountId, Vec<FeeTier>>,
+    account_volumes: LookupMap<String, Vec<DailyVolume>>,
+    referral_share_bps: u16,
+    referral_balances: UnorderedMap<String, Balance>,
+    referrer_stats: LookupMap<String, ReferrerStats>,
+    creator_share_bps: u16,
+    market_creators: LookupMap<AccountId, AccountId>,
+    creator_balances: UnorderedMap<String, Balance>,
 }
 
 #[near]
 impl FeeCollector {
+    /// Thresholds start at zero and there are no approvers, so nothing can be
+    /// withdrawn until a withdrawal policy or threshold has cleared its delay.
     #[init]
-    pub fn new(owner: AccountId, treasury: AccountId) -> Self {
+    pub fn new(owner: AccountId, treasury: AccountId, guardian: AccountId) -> Self {
         assert!(!env::state_exists(), "Already initialized");
         Self {
             owner,
             treasury,
+            guardian,
+            factory: None,
             authorized_markets: UnorderedMap::new(b"m"),
             collected_fees: UnorderedMap::new(b"f"),
+            insurance_share_bps: 0,
+            insurance_balances: UnorderedMap::new(b"i"),
+            insurance_caps: UnorderedMap::new(b"c"),
+            insurance_drawn: UnorderedMap::new(b"w"),
+            insurance_draws: Vector::new(b"d"),
+            approvers: UnorderedSet::new(b"a"),
+            required_approvals: 1,
+            withdrawal_timelock_secs: DEFAULT_WITHDRAWAL_TIMELOCK_SECS,
+            withdrawal_thresholds: UnorderedMap::new(b"t"),
+            direct_withdrawals: LookupMap::new(b"q"),
+            withdrawal_proposals: Vector::new(b"p"),
+            policy_changes: Vector::new(b"y"),
+            fee_tiers: UnorderedMap::new(b"x"),
+            account_volumes: LookupMap::new(b"v"),
+            referral_share_bps: 0,
+            referral_balances: UnorderedMap::new(b"r"),
+            referrer_stats: LookupMap::new(b"s"),
+            creator_share_bps: 0,
+            market_creators: LookupMap::new(b"o"),
+            creator_balances: UnorderedMap::new(b"e"),
         }
     }
 
-    pub fn authorize_market(&mut self, market: AccountId) {
+    pub fn set_factory(&mut self, factory: Option<AccountId>) {
         self.assert_owner();
+        self.factory = factory;
+        match &self.factory {
+            Some(factory) => env::log_str(&format!("Factory set to {}", factory)),
+            None => env::log_str("Factory cleared"),
+        }
+    }
+
+    pub fn authorize_market(&mut self, market: AccountId) {
+        self.assert_owner_or_factory();
         self.authorized_markets.insert(&market, &true);
         env::log_str(&format!("Market {} authorized", market));
     }
 
     pub fn revoke_market(&mut self, market: AccountId) {
-        self.assert_owner();
+        self.assert_owner_or_factory();
         self.authorized_markets.remove(&market);
         env::log_str(&format!("Market {} revoked", market));
     }
 
+    pub fn set_market_creator(&mut self, market: AccountId, creator: Option<AccountId>) {
+        self.assert_owner_or_factory();
+        match creator {
+            Some(creator) => {
+                env::log_str(&format!("Market {} creator set to {}", market, creator));
+                self.market_creators.insert(&market, &creator);
+            }
+            None => {
+                self.market_creators.remove(&market);
+                env::log_str(&format!("Market {} creator cleared", market));
+            }
+        }
+    }
+
     pub fn set_treasury(&mut self, treasury: AccountId) {
         self.assert_owner();
-        self.treasury = treasury;
         env::log_str(&format!("Treasury set to {}", treasury));
+        self.treasury = treasury;
+    }
+
+    pub fn set_guardian(&mut self, guardian: AccountId) {
+        self.assert_owner();
+        self.guardian = guardian;
+        env::log_str(&format!("Guardian set to {}", self.guardian));
+    }
+
+    pub fn set_insurance_share_bps(&mut self, insurance_share_bps: u16) {
+        self.assert_owner();
+        assert!(insurance_share_bps <= MAX_BPS, "Share exceeds 100%");
+        self.insurance_share_bps = insurance_share_bps;
+        env::log_str(&format!("Insurance share set to {} bps", insurance_share_bps));
+    }
+
+    pub fn set_insurance_cap(&mut self, token: AccountId, cap: U128) {
+        self.assert_owner();
+        self.insurance_caps.insert(&token, &cap.0);
+        env::log_str(&format!("Insurance cap per market for {} set to {}", token, cap.0));
+    }
+
+    pub fn set_withdrawal_policy(
+        &mut self,
+        approvers: Vec<AccountId>,
+        required_approvals: u32,
+        timelock_secs: u64,
+    ) -> u64 {
+        self.assert_owner();
+        assert!(required_approvals > 0, "At least one approval required");
+        let mut unique = approvers.clone();
+        unique.sort();
+        unique.dedup();
+        assert!(
+            required_approvals as usize <= unique.len(),
+            "Not enough approvers for required approvals"
+        );
+
+        self.internal_queue_policy_change(PolicyChangeKind::Policy {
+            approvers: unique,
+            required_approvals,
+            timelock_secs,
+        })
+    }
+
+    // Lowering a threshold only tightens the rules, so it applies at once.
+    pub fn set_withdrawal_threshold(&mut self, token: AccountId, threshold: U128) -> Option<u64> {
+        self.assert_owner();
+        if threshold.0 <= self.withdrawal_thresholds.get(&token).unwrap_or(0) {
+            self.withdrawal_thresholds.insert(&token, &threshold.0);
+            env::log_str(&format!("Withdrawal threshold for {} set to {}", token, threshold.0));
+            return None;
+        }
+        Some(self.internal_queue_policy_change(PolicyChangeKind::Threshold { token, threshold }))
+    }
+
+    pub fn apply_policy_change(&mut self, change_id: u64) {
+        self.assert_owner();
+        let mut change = self.policy_changes.get(change_id).expect("Policy change not found");
+        assert!(change.status == ProposalStatus::Queued, "Policy change is not queued");
+        assert!(env::block_timestamp() >= change.executable_at, "Policy change delay has not expired");
+
+        match &change.change {
+            PolicyChangeKind::Policy { approvers, required_approvals, timelock_secs } => {
+                self.approvers.clear();
+                for approver in approvers.iter() {
+                    self.approvers.insert(approver);
+                }
+                self.required_approvals = *required_approvals;
+                self.withdrawal_timelock_secs = *timelock_secs;
+                env::log_str(&format!(
+                    "Withdrawal policy set: {}-of-{} approvals, {}s timelock",
+                    required_approvals,
+                    self.approvers.len(),
+                    timelock_secs
+                ));
+            }
+            PolicyChangeKind::Threshold { token, threshold } => {
+                self.withdrawal_thresholds.insert(token, &threshold.0);
+                env::log_str(&format!("Withdrawal threshold for {} set to {}", token, threshold.0));
+            }
+        }
+
+        change.status = ProposalStatus::Executed;
+        change.resolved_at = Some(env::block_timestamp());
+        self.policy_changes.replace(change_id, &change);
+    }
+
+    pub fn cancel_policy_change(&mut self, change_id: u64) {
+        self.assert_guardian();
+        let mut change = self.policy_changes.get(change_id).expect("Policy change not found");
+        assert!(change.status == ProposalStatus::Queued, "Policy change is not queued");
+
+        change.status = ProposalStatus::Cancelled;
+        change.resolved_at = Some(env::block_timestamp());
+        self.policy_changes.replace(change_id, &change);
+
+        env::log_str(&format!("Policy change {} cancelled", change_id));
+    }
+
+    pub fn set_referral_share_bps(&mut self, referral_share_bps: u16) {
+        self.assert_owner();
+        assert!(referral_share_bps <= MAX_BPS, "Share exceeds 100%");
+        self.referral_share_bps = referral_share_bps;
+        env::log_str(&format!("Referral share set to {} bps", referral_share_bps));
+    }
+
+    pub fn set_creator_share_bps(&mut self, creator_share_bps: u16) {
+        self.assert_owner();
+        assert!(creator_share_bps <= MAX_BPS, "Share exceeds 100%");
+        self.creator_share_bps = creator_share_bps;
+        env::log_str(&format!("Creator share set to {} bps", creator_share_bps));
+    }
+
+    pub fn set_fee_tiers(&mut self, token: AccountId, tiers: Vec<FeeTier>) {
+        self.assert_owner();
+        for tier in tiers.iter() {
+            assert!(tier.discount_bps <= MAX_BPS, "Discount exceeds 100%");
+        }
+        for pair in tiers.windows(2) {
+            assert!(
+                pair[0].min_volume.0 < pair[1].min_volume.0,
+                "Tiers must be sorted by ascending volume"
+            );
+        }
+
+        if tiers.is_empty() {
+            self.fee_tiers.remove(&token);
+        } else {
+            self.fee_tiers.insert(&token, &tiers);
+        }
+        env::log_str(&format!("Fee tiers for {} updated", token));
     }
 
+    // The threshold caps everything withdrawn this way over the rolling
+    // window, so it cannot be drained in several smaller calls.
     pub fn withdraw_fees(&mut self, token: AccountId, amount: Option<U128>) -> Promise {
         self.assert_owner();
         
         let collected = self.collected_fees.get(&token).unwrap_or(0);
         let withdraw_amount = amount.map(|a| a.0).unwrap_or(collected);
+        let threshold = self.withdrawal_thresholds.get(&token).unwrap_or(0);
+        let today = env::block_timestamp() / DAY_NS;
         
-        assert!(withdraw_amount <= collected, "Insufficient collected fees");
+        let mut buckets: Vec<DailyVolume> = self
+            .direct_withdrawals
+            .get(&token)
+            .unwrap_or_else(Vec::new)
+            .into_iter()
+            .filter(|bucket| bucket.day + WITHDRAWAL_WINDOW_DAYS > today)
+            .collect();
+        let withdrawn: Balance = buckets.iter().map(|bucket| bucket.volume).sum();
+        assert!(
+            withdrawn + withdraw_amount <= threshold,
+            "Amount exceeds withdrawal threshold, use propose_withdrawal"
+        );
         
-        let new_balance = collected - withdraw_amount;
-        if new_balance == 0 {
-            self.collected_fees.remove(&token);
-        } else {
-            self.collected_fees.insert(&token, &new_balance);
+        match buckets.last_mut() {
+            Some(bucket) if bucket.day == today => bucket.volume += withdraw_amount,
+            _ => buckets.push(DailyVolume { day: today, volume: withdraw_amount }),
         }
+        self.direct_withdrawals.insert(&token, &buckets);
+        
+        self.internal_withdraw(token, withdraw_amount, self.treasury.clone())
+    }
 
-        ext_ft::ext(token)
+    pub fn propose_withdrawal(&mut self, token: AccountId, amount: U128) -> u64 {
+        let proposer = env::predecessor_account_id();
+        let is_approver = self.approvers.contains(&proposer);
+        assert!(
+            proposer == self.owner || is_approver,
+            "Only owner or approvers can propose withdrawals"
+        );
+        assert!(amount.0 > 0, "Amount must be positive");
+        assert!(
+            amount.0 <= self.collected_fees.get(&token).unwrap_or(0),
+            "Insufficient collected fees"
+        );
+
+        let proposal_id = self.withdrawal_proposals.len();
+        let mut proposal = WithdrawalProposal {
+            proposal_id,
+            token,
+            amount,
+            receiver: self.treasury.clone(),
+            proposer: proposer.clone(),
+            approvals: Vec::new(),
+            status: ProposalStatus::Pending,
+            created_at: env::block_timestamp(),
+            executable_at: None,
+            resolved_at: None,
+        };
+        if is_approver {
+            self.internal_add_approval(&mut proposal, proposer.clone());
+        }
+        self.withdrawal_proposals.push(&proposal);
+
+        env::log_str(&format!(
+            "Withdrawal {} proposed by {}: {} of token {} to {}",
+            proposal_id, proposer, amount.0, proposal.token, proposal.receiver
+        ));
+        proposal_id
+    }
+
+    pub fn approve_withdrawal(&mut self, proposal_id: u64) {
+        let approver = env::predecessor_account_id();
+        assert!(self.approvers.contains(&approver), "Only approvers can call this method");
+
+        // Queued proposals still take approvals, to replace any from approvers
+        // removed since they were given.
+        let mut proposal = self.withdrawal_proposals.get(proposal_id).expect("Proposal not found");
+        assert!(
+            proposal.status == ProposalStatus::Pending || proposal.status == ProposalStatus::Queued,
+            "Proposal is not pending"
+        );
+        assert!(!proposal.approvals.contains(&approver), "Already approved");
+
+        self.internal_add_approval(&mut proposal, approver.clone());
+        self.withdrawal_proposals.replace(proposal_id, &proposal);
+
+        env::log_str(&format!("Withdrawal {} approved by {}", proposal_id, approver));
+    }
+
+    pub fn execute_withdrawal(&mut self, proposal_id: u64) -> Promise {
+        let caller = env::predecessor_account_id();
+        assert!(
+            caller == self.owner || self.approvers.contains(&caller),
+            "Only owner or approvers can execute withdrawals"
+        );
+
+        let mut proposal = self.withdrawal_proposals.get(proposal_id).expect("Proposal not found");
+        assert!(proposal.status == ProposalStatus::Queued, "Proposal is not queued");
+        assert!(
+            env::block_timestamp() >= proposal.executable_at.unwrap_or(u64::MAX),
+            "Timelock has not expired"
+        );
+        assert!(
+            self.current_approvals(&proposal) >= self.required_approvals,
+            "Not enough approvals from current approvers"
+        );
+
+        proposal.status = ProposalStatus::Executed;
+        proposal.resolved_at = Some(env::block_timestamp());
+        self.withdrawal_proposals.replace(proposal_id, &proposal);
+
+        env::log_str(&format!("Withdrawal {} executed", proposal_id));
+
+        self.internal_withdraw(proposal.token, proposal.amount.0, proposal.receiver)
+    }
+
+    pub fn cancel_withdrawal(&mut self, proposal_id: u64) {
+        self.assert_guardian();
+        let mut proposal = self.withdrawal_proposals.get(proposal_id).expect("Proposal not found");
+        assert!(
+            proposal.status == ProposalStatus::Pending || proposal.status == ProposalStatus::Queued,
+            "Proposal already resolved"
+        );
+
+        proposal.status = ProposalStatus::Cancelled;
+        proposal.resolved_at = Some(env::block_timestamp());
+        self.withdrawal_proposals.replace(proposal_id, &proposal);
+
+        env::log_str(&format!("Withdrawal {} cancelled", proposal_id));
+    }
+
+    pub fn claim_referral_rebates(&mut self, token: AccountId) -> Promise {
+        let referrer = env::predecessor_account_id();
+        let key = Self::referral_key(&referrer, &token);
+        let amount = self.referral_balances.get(&key).unwrap_or(0);
+        assert!(amount > 0, "Nothing to claim");
+
+        self.referral_balances.remove(&key);
+        let mut stats = self.referrer_stats.get(&key).unwrap_or_default();
+        stats.rebates_claimed = U128(stats.rebates_claimed.0 + amount);
+        self.referrer_stats.insert(&key, &stats);
+
+        ext_ft::ext(token.clone())
             .with_static_gas(FT_TRANSFER_GAS)
+            .with_attached_deposit(NearToken::from_yoctonear(1))
             .ft_transfer(
-                self.treasury.clone(),
-                U128(withdraw_amount),
-                Some("Fee withdrawal".to_string()),
+                referrer.clone(),
+                U128(amount),
+                Some("Referral rebate".to_string()),
+            )
+            .then(
+                Self::ext(env::current_account_id())
+                    .with_static_gas(CALLBACK_GAS)
+                    .on_referral_rebate_claimed(referrer, token, U128(amount))
+            )
+    }
+
+    #[private]
+    pub fn on_referral_rebate_claimed(&mut self, referrer: AccountId, token: AccountId, amount: U128) -> bool {
+        if let PromiseResult::Successful(_) = env::promise_result(0) {
+            env::log_str(&format!("Referral rebate claimed: {} of token {} by {}", amount.0, token, referrer));
+            return true;
+        }
+
+        let key = Self::referral_key(&referrer, &token);
+        let balance = self.referral_balances.get(&key).unwrap_or(0);
+        self.referral_balances.insert(&key, &(balance + amount.0));
+        let mut stats = self.referrer_stats.get(&key).unwrap_or_default();
+        stats.rebates_claimed = U128(stats.rebates_claimed.0 - amount.0);
+        self.referrer_stats.insert(&key, &stats);
+
+        env::log_str(&format!("Referral rebate claim failed, restored {} to {}", amount.0, referrer));
+        false
+    }
+
+    pub fn claim_creator_fees(&mut self, token: AccountId) -> Promise {
+        let creator = env::predecessor_account_id();
+        let key = Self::creator_key(&creator, &token);
+        let amount = self.creator_balances.get(&key).unwrap_or(0);
+        assert!(amount > 0, "Nothing to claim");
+        self.creator_balances.remove(&key);
+
+        ext_ft::ext(token.clone())
+            .with_static_gas(FT_TRANSFER_GAS)
+            .with_attached_deposit(NearToken::from_yoctonear(1))
+            .ft_transfer(
+                creator.clone(),
+                U128(amount),
+                Some("Creator fee share".to_string()),
+            )
+            .then(
+                Self::ext(env::current_account_id())
+                    .with_static_gas(CALLBACK_GAS)
+                    .on_creator_fees_claimed(creator, token, U128(amount))
             )
     }
 
-    pub fn record_fee(&mut self, token: AccountId, amount: Balance) {
+    #[private]
+    pub fn on_creator_fees_claimed(&mut self, creator: AccountId, token: AccountId, amount: U128) -> bool {
+        if let PromiseResult::Successful(_) = env::promise_result(0) {
+            env::log_str(&format!("Creator fees claimed: {} of token {} by {}", amount.0, token, creator));
+            return true;
+        }
+
+        let key = Self::creator_key(&creator, &token);
+        let balance = self.creator_balances.get(&key).unwrap_or(0);
+        self.creator_balances.insert(&key, &(balance + amount.0));
+        env::log_str(&format!("Creator fee claim failed, restored {} to {}", amount.0, creator));
+        false
+    }
+
+    pub fn record_volume(&mut self, account: AccountId, token: AccountId, amount: U128) {
+        self.assert_authorized_market();
+        let key = Self::volume_key(&account, &token);
+        let today = env::block_timestamp() / DAY_NS;
+
+        let mut buckets: Vec<DailyVolume> = self
+            .account_volumes
+            .get(&key)
+            .unwrap_or_else(Vec::new)
+            .into_iter()
+            .filter(|bucket| bucket.day + VOLUME_WINDOW_DAYS > today)
+            .collect();
+        match buckets.last_mut() {
+            Some(bucket) if bucket.day == today => bucket.volume += amount.0,
+            _ => buckets.push(DailyVolume { day: today, volume: amount.0 }),
+        }
+        self.account_volumes.insert(&key, &buckets);
+    }
+
+    pub fn request_insurance_draw(&mut self, token: AccountId, amount: U128) -> u64 {
         self.assert_authorized_market();
-        let current = self.collected_fees.get(&token).unwrap_or(0);
-        self.collected_fees.insert(&token, &(current + amount));
-        env::log_str(&format!("Recorded fee: {} of token {}", amount, token));
+        assert!(amount.0 > 0, "Amount must be positive");
+
+        let market = env::predecessor_account_id();
+        let drawn = self.insurance_drawn.get(&Self::draw_key(&market, &token)).unwrap_or(0);
+        let cap = self.insurance_caps.get(&token).unwrap_or(0);
+        assert!(drawn + amount.0 <= cap, "Draw exceeds insurance cap");
+
+        let draw_id = self.insurance_draws.len();
+        self.insurance_draws.push(&InsuranceDraw {
+            draw_id,
+            market: market.clone(),
+            token: token.clone(),
+            amount,
+            paid_amount: U128(0),
+            status: DrawStatus::Pending,
+            requested_at: env::block_timestamp(),
+            resolved_at: None,
+            resolved_by: None,
+        });
+
+        env::log_str(&format!(
+            "Insurance draw {} requested by {}: {} of token {}",
+            draw_id, market, amount.0, token
+        ));
+        draw_id
+    }
+
+    pub fn approve_insurance_draw(&mut self, draw_id: u64) -> Promise {
+        self.assert_guardian();
+        let mut draw = self.insurance_draws.get(draw_id).expect("Draw not found");
+        assert!(draw.status == DrawStatus::Pending, "Draw is not pending");
+
+        let key = Self::draw_key(&draw.market, &draw.token);
+        let drawn = self.insurance_drawn.get(&key).unwrap_or(0);
+        let cap = self.insurance_caps.get(&draw.token).unwrap_or(0);
+        assert!(drawn + draw.amount.0 <= cap, "Draw exceeds insurance cap");
+
+        let balance = self.insurance_balances.get(&draw.token).unwrap_or(0);
+        assert!(draw.amount.0 <= balance, "Insufficient insurance balance");
+
+        self.insurance_balances.insert(&draw.token, &(balance - draw.amount.0));
+        self.insurance_drawn.insert(&key, &(drawn + draw.amount.0));
+
+        draw.status = DrawStatus::Approved;
+        draw.resolved_at = Some(env::block_timestamp());
+        draw.resolved_by = Some(env::predecessor_account_id());
+        self.insurance_draws.replace(draw_id, &draw);
+
+        env::log_str(&format!("Insurance draw {} approved", draw_id));
+
+        ext_ft::ext(draw.token.clone())
+            .with_static_gas(FT_TRANSFER_CALL_GAS)
+            .with_attached_deposit(NearToken::from_yoctonear(1))
+            .ft_transfer_call(
+                draw.market.clone(),
+                draw.amount,
+                Some(format!("Insurance draw {}", draw_id)),
+                "insurance".to_string(),
+            )
+            .then(
+                Self::ext(env::current_account_id())
+                    .with_static_gas(CALLBACK_GAS)
+                    .on_insurance_draw_paid(draw_id)
+            )
+    }
+
+    pub fn reject_insurance_draw(&mut self, draw_id: u64) {
+        self.assert_guardian();
+        let mut draw = self.insurance_draws.get(draw_id).expect("Draw not found");
+        assert!(draw.status == DrawStatus::Pending, "Draw is not pending");
+
+        draw.status = DrawStatus::Rejected;
+        draw.resolved_at = Some(env::block_timestamp());
+        draw.resolved_by = Some(env::predecessor_account_id());
+        self.insurance_draws.replace(draw_id, &draw);
+
+        env::log_str(&format!("Insurance draw {} rejected", draw_id));
+    }
+
+    #[private]
+    pub fn on_insurance_draw_paid(&mut self, draw_id: u64) -> bool {
+        let mut draw = self.insurance_draws.get(draw_id).expect("Draw not found");
+        let used = match env::promise_result(0) {
+            PromiseResult::Successful(value) => near_sdk::serde_json::from_slice::<U128>(&value)
+                .map(|used| used.0.min(draw.amount.0))
+                .unwrap_or(draw.amount.0),
+            _ => 0,
+        };
+
+        let unused = draw.amount.0 - used;
+        if unused > 0 {
+            self.internal_add_insurance(&draw.token, unused);
+            let key = Self::draw_key(&draw.market, &draw.token);
+            let drawn = self.insurance_drawn.get(&key).unwrap_or(0);
+            self.insurance_drawn.insert(&key, &(drawn - unused));
+        }
+
+        draw.paid_amount = U128(used);
+        draw.status = if used > 0 { DrawStatus::Paid } else { DrawStatus::Failed };
+        self.insurance_draws.replace(draw_id, &draw);
+
+        env::log_str(&format!("Insurance draw {} paid {} of {}", draw_id, used, draw.amount.0));
+        used > 0
     }
 
     pub fn get_collected_fees(&self, token: AccountId) -> U128 {
@@ -92,6 +742,149 @@ impl FeeCollector {
         self.treasury.clone()
     }
 
+    pub fn get_withdrawal_policy(&self) -> WithdrawalPolicy {
+        WithdrawalPolicy {
+            approvers: self.approvers.to_vec(),
+            required_approvals: self.required_approvals,
+            timelock_secs: self.withdrawal_timelock_secs,
+        }
+    }
+
+    pub fn get_withdrawal_threshold(&self, token: AccountId) -> U128 {
+        U128(self.withdrawal_thresholds.get(&token).unwrap_or(0))
+    }
+
+    pub fn get_direct_withdrawn(&self, token: AccountId) -> U128 {
+        let today = env::block_timestamp() / DAY_NS;
+        U128(
+            self.direct_withdrawals
+                .get(&token)
+                .unwrap_or_else(Vec::new)
+                .iter()
+                .filter(|bucket| bucket.day + WITHDRAWAL_WINDOW_DAYS > today)
+                .map(|bucket| bucket.volume)
+                .sum()
+        )
+    }
+
+    pub fn get_withdrawal_proposal(&self, proposal_id: u64) -> Option<WithdrawalProposal> {
+        self.withdrawal_proposals.get(proposal_id)
+    }
+
+    pub fn get_withdrawal_proposals(&self, from_index: u64, limit: u64) -> Vec<WithdrawalProposal> {
+        self.withdrawal_proposals
+            .iter()
+            .skip(from_index as usize)
+            .take(limit as usize)
+            .collect()
+    }
+
+    pub fn get_withdrawal_proposal_count(&self) -> u64 {
+        self.withdrawal_proposals.len()
+    }
+
+    pub fn get_policy_change(&self, change_id: u64) -> Option<PolicyChange> {
+        self.policy_changes.get(change_id)
+    }
+
+    pub fn get_policy_changes(&self, from_index: u64, limit: u64) -> Vec<PolicyChange> {
+        self.policy_changes
+            .iter()
+            .skip(from_index as usize)
+            .take(limit as usize)
+            .collect()
+    }
+
+    pub fn get_referral_share_bps(&self) -> u16 {
+        self.referral_share_bps
+    }
+
+    pub fn get_referral_balance(&self, referrer: AccountId, token: AccountId) -> U128 {
+        U128(self.referral_balances.get(&Self::referral_key(&referrer, &token)).unwrap_or(0))
+    }
+
+    pub fn get_referrer_stats(&self, referrer: AccountId, token: AccountId) -> ReferrerStats {
+        self.referrer_stats
+            .get(&Self::referral_key(&referrer, &token))
+            .unwrap_or_default()
+    }
+
+    pub fn get_creator_share_bps(&self) -> u16 {
+        self.creator_share_bps
+    }
+
+    pub fn get_market_creator(&self, market: AccountId) -> Option<AccountId> {
+        self.market_creators.get(&market)
+    }
+
+    pub fn get_creator_balance(&self, creator: AccountId, token: AccountId) -> U128 {
+        U128(self.creator_balances.get(&Self::creator_key(&creator, &token)).unwrap_or(0))
+    }
+
+    pub fn get_fee_tiers(&self, token: AccountId) -> Vec<FeeTier> {
+        self.fee_tiers.get(&token).unwrap_or_else(Vec::new)
+    }
+
+    pub fn get_account_volume(&self, account: AccountId, token: AccountId) -> U128 {
+        U128(self.internal_rolling_volume(&account, &token))
+    }
+
+    pub fn get_fee_discount_bps(&self, account: AccountId, token: AccountId) -> u16 {
+        let volume = self.internal_rolling_volume(&account, &token);
+        self.fee_tiers
+            .get(&token)
+            .unwrap_or_else(Vec::new)
+            .iter()
+            .filter(|tier| volume >= tier.min_volume.0)
+            .map(|tier| tier.discount_bps)
+            .last()
+            .unwrap_or(0)
+    }
+
+    pub fn get_effective_fee_bps(&self, account: AccountId, token: AccountId, base_fee_bps: u16) -> u16 {
+        let discount_bps = self.get_fee_discount_bps(account, token);
+        ((base_fee_bps as u32 * (MAX_BPS - discount_bps) as u32) / MAX_BPS as u32) as u16
+    }
+
+    pub fn get_insurance_config(&self) -> InsuranceConfig {
+        InsuranceConfig {
+            guardian: self.guardian.clone(),
+            insurance_share_bps: self.insurance_share_bps,
+        }
+    }
+
+    pub fn get_insurance_balance(&self, token: AccountId) -> U128 {
+        U128(self.insurance_balances.get(&token).unwrap_or(0))
+    }
+
+    pub fn get_insurance_cap(&self, token: AccountId) -> U128 {
+        U128(self.insurance_caps.get(&token).unwrap_or(0))
+    }
+
+    pub fn get_market_insurance_drawn(&self, market: AccountId, token: AccountId) -> U128 {
+        U128(self.insurance_drawn.get(&Self::draw_key(&market, &token)).unwrap_or(0))
+    }
+
+    pub fn get_insurance_draw(&self, draw_id: u64) -> Option<InsuranceDraw> {
+        self.insurance_draws.get(draw_id)
+    }
+
+    pub fn get_insurance_draws(&self, from_index: u64, limit: u64) -> Vec<InsuranceDraw> {
+        self.insurance_draws
+            .iter()
+            .skip(from_index as usize)
+            .take(limit as usize)
+            .collect()
+    }
+
+    pub fn get_insurance_draw_count(&self) -> u64 {
+        self.insurance_draws.len()
+    }
+
+    pub fn get_factory(&self) -> Option<AccountId> {
+        self.factory.clone()
+    }
+
     pub fn is_market_authorized(&self, market: AccountId) -> bool {
         self.authorized_markets.get(&market).unwrap_or(false)
     }
@@ -104,6 +897,22 @@ impl FeeCollector {
         );
     }
 
+    fn assert_guardian(&self) {
+        assert_eq!(
+            env::predecessor_account_id(),
+            self.guardian,
+            "Only guardian can call this method"
+        );
+    }
+
+    fn assert_owner_or_factory(&self) {
+        let caller = env::predecessor_account_id();
+        assert!(
+            caller == self.owner || self.factory.as_ref() == Some(&caller),
+            "Only owner or factory can call this method"
+        );
+    }
+
     fn assert_authorized_market(&self) {
         let caller = env::predecessor_account_id();
         assert!(
@@ -111,6 +920,159 @@ impl FeeCollector {
             "Only authorized markets can call this method"
         );
     }
+
+    fn internal_withdraw(&mut self, token: AccountId, amount: Balance, receiver: AccountId) -> Promise {
+        let collected = self.collected_fees.get(&token).unwrap_or(0);
+        assert!(amount <= collected, "Insufficient collected fees");
+
+        let new_balance = collected - amount;
+        if new_balance == 0 {
+            self.collected_fees.remove(&token);
+        } else {
+            self.collected_fees.insert(&token, &new_balance);
+        }
+
+        ext_ft::ext(token)
+            .with_static_gas(FT_TRANSFER_GAS)
+            .with_attached_deposit(NearToken::from_yoctonear(1))
+            .ft_transfer(
+                receiver,
+                U128(amount),
+                Some("Fee withdrawal".to_string()),
+            )
+    }
+
+    fn internal_queue_policy_change(&mut self, change: PolicyChangeKind) -> u64 {
+        let now = env::block_timestamp();
+        let delay_secs = self.withdrawal_timelock_secs.max(MIN_POLICY_CHANGE_DELAY_SECS);
+        let change_id = self.policy_changes.len();
+        let change = PolicyChange {
+            change_id,
+            change,
+            status: ProposalStatus::Queued,
+            proposed_at: now,
+            executable_at: now + delay_secs * 1_000_000_000,
+            resolved_at: None,
+        };
+        self.policy_changes.push(&change);
+        env::log_str(&format!(
+            "Policy change {} queued, executable at {}",
+            change_id, change.executable_at
+        ));
+        change_id
+    }
+
+    // Approvals given by accounts that have since been removed do not count.
+    fn current_approvals(&self, proposal: &WithdrawalProposal) -> u32 {
+        proposal
+            .approvals
+            .iter()
+            .filter(|approver| self.approvers.contains(approver))
+            .count() as u32
+    }
+
+    fn internal_add_approval(&self, proposal: &mut WithdrawalProposal, approver: AccountId) {
+        proposal.approvals.push(approver);
+        if proposal.status == ProposalStatus::Pending && self.current_approvals(proposal) >= self.required_approvals {
+            proposal.status = ProposalStatus::Queued;
+            proposal.executable_at =
+                Some(env::block_timestamp() + self.withdrawal_timelock_secs * 1_000_000_000);
+            env::log_str(&format!(
+                "Withdrawal {} queued, executable at {}",
+                proposal.proposal_id,
+                proposal.executable_at.unwrap()
+            ));
+        }
+    }
+
+    fn internal_record_fee(&mut self, token: &AccountId, amount: Balance) {
+        let insurance = (amount * self.insurance_share_bps as u128) / MAX_BPS as u128;
+        if insurance > 0 {
+            self.internal_add_insurance(token, insurance);
+        }
+        let current = self.collected_fees.get(token).unwrap_or(0);
+        self.collected_fees.insert(token, &(current + amount - insurance));
+        env::log_str(&format!(
+            "Recorded fee: {} of token {} ({} to insurance)",
+            amount, token, insurance
+        ));
+    }
+
+    fn internal_record_referral_fee(
+        &mut self,
+        market: &AccountId,
+        token: &AccountId,
+        amount: Balance,
+        referral: ReferralFee,
+    ) {
+        let ReferralFee { referrer, account } = referral;
+        let rebate = (amount * self.referral_share_bps as u128) / MAX_BPS as u128;
+        let key = Self::referral_key(&referrer, token);
+
+        let balance = self.referral_balances.get(&key).unwrap_or(0);
+        self.referral_balances.insert(&key, &(balance + rebate));
+
+        let mut stats = self.referrer_stats.get(&key).unwrap_or_default();
+        stats.referral_count += 1;
+        stats.fees_referred = U128(stats.fees_referred.0 + amount);
+        stats.rebates_accrued = U128(stats.rebates_accrued.0 + rebate);
+        self.referrer_stats.insert(&key, &stats);
+
+        env::log_str(&format!(
+            "Referral rebate: {} of token {} to {} for {}",
+            rebate, token, referrer, account
+        ));
+        self.internal_record_market_fee(market, token, amount - rebate);
+    }
+
+    fn internal_record_market_fee(&mut self, market: &AccountId, token: &AccountId, amount: Balance) {
+        let creator_share = match self.market_creators.get(market) {
+            Some(creator) => {
+                let share = (amount * self.creator_share_bps as u128) / MAX_BPS as u128;
+                if share > 0 {
+                    let key = Self::creator_key(&creator, token);
+                    let balance = self.creator_balances.get(&key).unwrap_or(0);
+                    self.creator_balances.insert(&key, &(balance + share));
+                    env::log_str(&format!("Creator share: {} of token {} to {}", share, token, creator));
+                }
+                share
+            }
+            None => 0,
+        };
+        self.internal_record_fee(token, amount - creator_share);
+    }
+
+    fn internal_add_insurance(&mut self, token: &AccountId, amount: Balance) {
+        let current = self.insurance_balances.get(token).unwrap_or(0);
+        self.insurance_balances.insert(token, &(current + amount));
+    }
+
+    fn internal_rolling_volume(&self, account: &AccountId, token: &AccountId) -> Balance {
+        let today = env::block_timestamp() / DAY_NS;
+        self.account_volumes
+            .get(&Self::volume_key(account, token))
+            .unwrap_or_else(Vec::new)
+            .iter()
+            .filter(|bucket| bucket.day + VOLUME_WINDOW_DAYS > today)
+            .map(|bucket| bucket.volume)
+            .sum()
+    }
+
+    fn referral_key(referrer: &AccountId, token: &AccountId) -> String {
+        format!("{}:{}", referrer, token)
+    }
+
+    fn creator_key(creator: &AccountId, token: &AccountId) -> String {
+        format!("{}:{}", creator, token)
+    }
+
+    fn volume_key(account: &AccountId, token: &AccountId) -> String {
+        format!("{}:{}", account, token)
+    }
+
+    fn draw_key(market: &AccountId, token: &AccountId) -> String {
+        format!("{}:{}", market, token)
+    }
 }
 
 #[near]
@@ -120,10 +1082,16 @@ impl FungibleTokenReceiver for FeeCollector {
         sender_id: AccountId,
         amount: U128,
         msg: String,
-    ) -> U128 {
+    ) -> PromiseOrValue<U128> {
         let token = env::predecessor_account_id();
         
-        if msg == "fee" {
+        // Markets pay their fees in here as they charge them, so every credited
+        // share is backed by tokens this contract holds.
+        let from_market = self.authorized_markets.get(&sender_id).unwrap_or(false);
+        let unused = if msg == "fee" && from_market {
+            self.internal_record_market_fee(&sender_id, &token, amount.0);
+            U128(0)
+        } else if msg == "fee" {
             let current = self.collected_fees.get(&token).unwrap_or(0);
             self.collected_fees.insert(&token, &(current + amount.0));
             env::log_str(&format!(
@@ -131,8 +1099,111 @@ impl FungibleTokenReceiver for FeeCollector {
                 amount.0, token, sender_id
             ));
             U128(0)
+        } else if msg == "dust" {
+            // Archive sweeps carry no fee revenue, so they skip the creator share.
+            let current = self.collected_fees.get(&token).unwrap_or(0);
+            self.collected_fees.insert(&token, &(current + amount.0));
+            env::log_str(&format!(
+                "Received dust: {} of token {} from {}",
+                amount.0, token, sender_id
+            ));
+            U128(0)
+        } else if msg == "insurance" {
+            self.internal_add_insurance(&token, amount.0);
+            env::log_str(&format!(
+                "Insurance funded: {} of token {} from {}",
+                amount.0, token, sender_id
+            ));
+            U128(0)
+        } else if let (true, Ok(referral)) = (from_market, near_sdk::serde_json::from_str::<ReferralFee>(&msg)) {
+            self.internal_record_referral_fee(&sender_id, &token, amount.0, referral);
+            U128(0)
         } else {
             amount
-        }
+        };
+        PromiseOrValue::Value(unused)
+    }
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use near_sdk::test_utils::VMContextBuilder;
+    use near_sdk::testing_env;
+
+    fn account(id: &str) -> AccountId {
+        id.parse().unwrap()
+    }
+
+    fn set_context(predecessor: &str, block_timestamp: u64) {
+        let context = VMContextBuilder::new()
+            .current_account_id(account("fees.near"))
+            .predecessor_account_id(account(predecessor))
+            .block_timestamp(block_timestamp)
+            .build();
+        testing_env!(context);
+    }
+
+    fn setup() -> FeeCollector {
+        set_context("owner.near", 0);
+        FeeCollector::new(account("owner.near"), account("treasury.near"), account("guardian.near"))
+    }
+
+    // Collects 1000 of usdc.near and lets 100 of it out directly, from day one.
+    fn setup_with_threshold() -> FeeCollector {
+        let mut collector = setup();
+        set_context("usdc.near", 0);
+        let _ = collector.ft_on_transfer(account("payer.near"), U128(1000), "fee".to_string());
+
+        set_context("owner.near", 0);
+        let change_id = collector.set_withdrawal_threshold(account("usdc.near"), U128(100)).unwrap();
+        set_context("owner.near", DAY_NS);
+        collector.apply_policy_change(change_id);
+        collector
+    }
+
+    #[test]
+    fn test_fee_tier_discount() {
+        let mut collector = setup();
+        collector.set_fee_tiers(
+            account("usdc.near"),
+            vec![
+                FeeTier { min_volume: U128(0), discount_bps: 1000 },
+                FeeTier { min_volume: U128(500), discount_bps: 2500 },
+            ],
+        );
+        collector.authorize_market(account("market-0.factory.near"));
+        assert_eq!(collector.get_fee_discount_bps(account("user.near"), account("usdc.near")), 1000);
+        assert_eq!(collector.get_fee_discount_bps(account("user.near"), account("wrap.near")), 0);
+
+        set_context("market-0.factory.near", 0);
+        collector.record_volume(account("user.near"), account("usdc.near"), U128(500));
+        assert_eq!(collector.get_fee_discount_bps(account("user.near"), account("usdc.near")), 2500);
+        assert_eq!(collector.get_effective_fee_bps(account("user.near"), account("usdc.near"), 30), 22);
+
+        // Volume older than the window no longer counts.
+        set_context("market-0.factory.near", DAY_NS * VOLUME_WINDOW_DAYS);
+        assert_eq!(collector.get_fee_discount_bps(account("user.near"), account("usdc.near")), 1000);
+    }
+
+    #[test]
+    #[should_panic(expected = "Amount exceeds withdrawal threshold")]
+    fn test_direct_withdrawals_share_the_threshold() {
+        let mut collector = setup_with_threshold();
+        let _ = collector.withdraw_fees(account("usdc.near"), Some(U128(60)));
+        let _ = collector.withdraw_fees(account("usdc.near"), Some(U128(40)));
+        assert_eq!(collector.get_direct_withdrawn(account("usdc.near")), U128(100));
+
+        let _ = collector.withdraw_fees(account("usdc.near"), Some(U128(1)));
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn test_direct_withdrawal_window_rolls() {
+        let mut collector = setup_with_threshold();
+        let _ = collector.withdraw_fees(account("usdc.near"), Some(U128(100)));
+
+        set_context("owner.near", DAY_NS * (1 + WITHDRAWAL_WINDOW_DAYS));
+        assert_eq!(collector.get_direct_withdrawn(account("usdc.near")), U128(0));
+        let _ = collector.withdraw_fees(account("usdc.near"), Some(U128(100)));
+        assert_eq!(collector.get_collected_fees(account("usdc.near")), U128(800));
+    }
+}
@@ -1,14 +1,43 @@
 use near_sdk::borsh::{BorshDeserialize, BorshSerialize};
-use near_sdk::collections::{UnorderedMap, UnorderedSet};
-use near_sdk::json_types::U128;
+use near_sdk::collections::{LookupMap, TreeMap, UnorderedMap, UnorderedSet, Vector};
+use near_sdk::json_types::{Base58CryptoHash, Base64VecU8, U128};
 use near_sdk::serde::{Deserialize, Serialize};
-use near_sdk::{env, ext_contract, near, require, AccountId, Balance, Gas, PanicOnDefault, Promise, PublicKey};
+use near_sdk::{env, ext_contract, near, require, AccountId, CryptoHash, Gas, NearToken, PanicOnDefault, Promise, PromiseOrValue, PromiseResult, PublicKey};
+
+type Balance = u128;
 
 const TGAS: u64 = 1_000_000_000_000;
 const DEPLOY_GAS: Gas = Gas::from_tgas(100);
 const CALLBACK_GAS: Gas = Gas::from_tgas(10);
+const FEE_COLLECTOR_GAS: Gas = Gas::from_tgas(5);
+const TEARDOWN_GAS: Gas = Gas::from_tgas(10);
+const ON_TEARDOWN_GAS: Gas = Gas::from_tgas(5);
+const ON_DEPLOYED_GAS: Gas = Gas::from_tgas(50);
 const MARKET_STORAGE: Balance = 10_000_000_000_000_000_000_000_000;
 const TOKEN_STORAGE: Balance = 5_000_000_000_000_000_000_000_000;
+const UPGRADE_GAS: Gas = Gas::from_tgas(50);
+const ON_UPGRADED_GAS: Gas = Gas::from_tgas(10);
+// Left over for upgrade_markets itself after the last dispatched market
+const UPGRADE_BATCH_RESERVE_GAS: Gas = Gas::from_tgas(15);
+const UPDATE_CONFIG_GAS: Gas = Gas::from_tgas(10);
+const DEFAULT_UPGRADE_VETO_WINDOW_SECS: u64 = 2 * 86_400;
+const MATURITY_BUCKET_NS: u64 = 86_400 * 1_000_000_000;
+const MAX_BPS: u128 = 10_000;
+// Market bounds and settlement prices are fixed-point with this many decimals
+const PRICE_DECIMALS: u8 = 24;
+const ORACLE_QUERY_GAS: Gas = Gas::from_tgas(5);
+const DEFAULT_MAX_FEE_BPS: u16 = 500;
+const DEFAULT_MIN_BOUND_WIDTH_BPS: u32 = 500;
+const DEFAULT_MAX_BOUND_WIDTH_BPS: u32 = 40_000;
+const DEFAULT_MIN_TIME_TO_MATURITY_SECS: u64 = 3_600;
+const DEFAULT_MAX_TIME_TO_MATURITY_SECS: u64 = 365 * 86_400;
+const MIN_SERIES_CADENCE_SECS: u64 = 3_600;
+const MAX_CAP_WINDOW_SECS: u64 = 365 * 86_400;
+const ON_ORACLE_PRICE_GAS: Gas = Gas::from_tgas(200);
+const POSITION_REGISTER_GAS: Gas = Gas::from_tgas(10);
+const SNAPSHOT_GAS: Gas = Gas::from_tgas(10);
+const CODE_CHUNK_PREFIX: &[u8] = b"CODE_CHUNK";
+const CODE_BLOB_PREFIX: &[u8] = b"CODE_BLOB";
 
 #[derive(BorshSerialize, BorshDeserialize, Serialize, Deserialize, Clone)]
 #[serde(crate = "near_sdk::serde")]
@@ -22,6 +51,98 @@ pub struct MarketParams {
     pub mint_fee_bps: u16,
     pub settle_fee_bps: u16,
     pub redeem_fee_bps: u16,
+    // Handed to the market at deploy and kept there, so it is not part of
+    // the stored params.
+    #[serde(default)]
+    #[borsh(skip)]
+    pub caps: Option<MarketCaps>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Default)]
+#[serde(crate = "near_sdk::serde")]
+pub struct MarketCaps {
+    #[serde(default)]
+    pub max_total_collateral: Option<U128>,
+    #[serde(default)]
+    pub max_mint_per_account: Option<U128>,
+    #[serde(default)]
+    pub max_mint_per_window: Option<U128>,
+    #[serde(default)]
+    pub window_secs: u64,
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+#[serde(crate = "near_sdk::serde")]
+pub struct RelativeMarketParams {
+    pub underlying: AccountId,
+    pub quote: AccountId,
+    pub maturity: u64,
+    pub lower_offset_bps: u16,
+    pub upper_offset_bps: u16,
+    pub tick_size: U128,
+    pub mint_fee_bps: u16,
+    pub settle_fee_bps: u16,
+    pub redeem_fee_bps: u16,
+    #[serde(default)]
+    pub caps: Option<MarketCaps>,
+}
+
+#[derive(BorshSerialize, BorshDeserialize, Serialize, Deserialize, Clone)]
+#[serde(crate = "near_sdk::serde")]
+pub struct ParamLimits {
+    pub min_fee_bps: u16,
+    pub max_fee_bps: u16,
+    pub min_bound_width_bps: u32,
+    pub max_bound_width_bps: u32,
+    pub min_time_to_maturity_secs: u64,
+    pub max_time_to_maturity_secs: u64,
+}
+
+#[derive(BorshSerialize, BorshDeserialize, Serialize, Deserialize, Clone, Default)]
+#[serde(crate = "near_sdk::serde")]
+pub struct ClaimTokenMetadataConfig {
+    pub long_icon: Option<String>,
+    pub short_icon: Option<String>,
+    pub reference_base: Option<String>,
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(crate = "near_sdk::serde")]
+pub struct UnderlyingListing {
+    pub underlying: AccountId,
+    pub quotes: Vec<AccountId>,
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(crate = "near_sdk::serde")]
+pub struct PriceData {
+    pub price: U128,
+    pub timestamp: u64,
+    pub decimals: u8,
+}
+
+#[derive(BorshSerialize, BorshDeserialize, Serialize, Deserialize, Clone, PartialEq)]
+#[serde(crate = "near_sdk::serde")]
+pub struct MarketConfig {
+    pub oracle: AccountId,
+    pub fee_collector: AccountId,
+    pub guardian: AccountId,
+}
+
+#[derive(BorshSerialize, BorshDeserialize, Serialize, Deserialize, Clone, PartialEq)]
+#[serde(crate = "near_sdk::serde")]
+pub enum MarketStatus {
+    Active,
+    Matured,
+    Settled,
+    Archived,
+}
+
+#[derive(BorshSerialize, BorshDeserialize, Serialize, Deserialize, Clone, Copy, PartialEq)]
+#[serde(crate = "near_sdk::serde")]
+pub enum TokenBackend {
+    Nep141,
+    Nep245,
 }
 
 #[derive(BorshSerialize, BorshDeserialize, Serialize, Deserialize)]
@@ -33,11 +154,155 @@ pub struct MarketInfo {
     pub params: MarketParams,
     pub created_at: u64,
     pub creator: AccountId,
+    pub code_version: String,
+    pub config: MarketConfig,
+    pub status: MarketStatus,
+    pub series_id: Option<String>,
+    pub bond: U128,
+    pub bond_slashed: bool,
+    pub token_backend: TokenBackend,
+}
+
+#[derive(BorshSerialize, BorshDeserialize, Serialize, Deserialize, Clone)]
+#[serde(crate = "near_sdk::serde")]
+pub struct MarketSeries {
+    pub series_id: String,
+    pub underlying: AccountId,
+    pub quote: AccountId,
+    pub cadence_secs: u64,
+    pub roll_lead_secs: u64,
+    pub next_maturity: u64,
+    pub strike_k: U128,
+    pub lower_width_bps: u16,
+    pub upper_width_bps: u16,
+    pub mint_fee_bps: u16,
+    pub settle_fee_bps: u16,
+    pub redeem_fee_bps: u16,
+    pub version: Option<String>,
+    pub creator: AccountId,
+    pub balance: U128,
+    pub latest_market: Option<String>,
+    pub roll_pending: bool,
+    pub active: bool,
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(crate = "near_sdk::serde")]
+pub struct MarketPage {
+    pub markets: Vec<MarketInfo>,
+    pub next_cursor: Option<String>,
+}
+
+#[derive(BorshSerialize, BorshDeserialize)]
+pub struct CodeUpload {
+    pub size: u64,
+    pub received: u64,
+    pub chunks: u32,
+}
+
+#[derive(BorshSerialize, BorshDeserialize, Serialize, Deserialize, Clone)]
+#[serde(crate = "near_sdk::serde")]
+pub struct CodeBlob {
+    pub code_hash: Base58CryptoHash,
+    pub size: u64,
+    pub uploaded_at: u64,
+}
+
+#[derive(BorshSerialize, BorshDeserialize, Serialize, Deserialize, Clone)]
+#[serde(crate = "near_sdk::serde")]
+pub struct CodeRelease {
+    pub version: String,
+    pub market_code_hash: Base58CryptoHash,
+    pub claim_token_code_hash: Base58CryptoHash,
+    pub release_notes: String,
+    pub published_at: u64,
+}
+
+#[derive(BorshSerialize, BorshDeserialize, Serialize, Deserialize, Clone)]
+#[serde(crate = "near_sdk::serde")]
+pub struct ScheduledUpgrade {
+    pub version: String,
+    pub scheduled_at: u64,
+    pub executable_at: u64,
+    pub vetoed: bool,
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(crate = "near_sdk::serde")]
+pub struct UpgradeBatch {
+    pub dispatched: u64,
+    /// Where the next batch should start, if markets in range were left
+    /// for lack of gas
+    pub next_index: Option<u64>,
+}
+
+#[derive(BorshSerialize, BorshDeserialize, Serialize, Deserialize)]
+#[serde(crate = "near_sdk::serde")]
+pub struct DeploymentFailure {
+    pub market_key: String,
+    pub market_id: AccountId,
+    pub creator: AccountId,
+    pub failed_steps: Vec<String>,
+    pub refunded: U128,
+    pub failed_at: u64,
+}
+
+#[ext_contract(ext_teardown)]
+trait Teardown {
+    fn teardown(&mut self, beneficiary: AccountId) -> Promise;
+    fn archive(&mut self, beneficiary: AccountId) -> Promise;
+}
+
+#[ext_contract(ext_fee_collector)]
+trait FeeCollector {
+    fn authorize_market(&mut self, market: AccountId);
+    fn revoke_market(&mut self, market: AccountId);
+    fn set_market_creator(&mut self, market: AccountId, creator: Option<AccountId>);
+}
+
+#[ext_contract(ext_claim_token)]
+trait ClaimToken {
+    fn snapshot(&mut self) -> u64;
+}
+
+#[ext_contract(ext_position_token)]
+trait PositionToken {
+    fn register_market(&mut self, market: AccountId) -> Vec<String>;
+    fn archive_market(&mut self, market: AccountId);
+}
+
+#[ext_contract(ext_oracle)]
+trait OracleRouter {
+    fn get_price(&self, underlying: AccountId, quote: AccountId) -> Option<PriceData>;
+    fn get_oracle_config(&self, underlying: AccountId, quote: AccountId) -> Option<near_sdk::serde_json::Value>;
 }
 
 #[ext_contract(ext_self)]
 trait SelfCallback {
-    fn on_market_deployed(&mut self, market_key: String, market_info: MarketInfo);
+    fn on_market_deployed(&mut self, market_key: String, market_info: MarketInfo, deposit: U128) -> bool;
+    fn on_oracle_price(
+        &mut self,
+        params: RelativeMarketParams,
+        version: String,
+        token_backend: TokenBackend,
+        creator: AccountId,
+        deposit: U128,
+        bond: U128,
+    ) -> PromiseOrValue<bool>;
+    fn on_market_upgraded(&mut self, market_key: String, version: String) -> bool;
+    fn on_series_account_torn_down(&mut self, series_id: String, storage: U128) -> bool;
+    fn on_config_propagated(&mut self, market_key: String, config: MarketConfig) -> bool;
+    fn on_underlying_checked(&mut self, underlying: AccountId, quote: AccountId) -> bool;
+}
+
+#[ext_contract(ext_market)]
+trait ForwardMarket {
+    fn update_config(
+        &mut self,
+        oracle: Option<AccountId>,
+        fee_collector: Option<AccountId>,
+        guardian: Option<AccountId>,
+    );
 }
 
 #[near(contract_state)]
@@ -50,11 +315,30 @@ pub struct ForwardFactory {
     markets: UnorderedMap<String, MarketInfo>,
     markets_by_creator: UnorderedMap<AccountId, Vec<String>>,
     all_market_keys: UnorderedSet<String>,
-    market_code: Vec<u8>,
-    long_token_code: Vec<u8>,
-    short_token_code: Vec<u8>,
+    market_keys_by_id: LookupMap<AccountId, String>,
+    index_by_underlying: TreeMap<String, String>,
+    index_by_quote: TreeMap<String, String>,
+    index_by_maturity: TreeMap<String, String>,
+    index_by_status: TreeMap<String, String>,
+    code_uploads: UnorderedMap<CryptoHash, CodeUpload>,
+    code_blobs: UnorderedMap<CryptoHash, CodeBlob>,
+    code_releases: UnorderedMap<String, CodeRelease>,
+    default_release: Option<String>,
+    scheduled_upgrades: UnorderedMap<String, ScheduledUpgrade>,
+    upgrade_veto_window_secs: u64,
     paused: bool,
     deploy_counter: u64,
+    deployment_failures: Vector<DeploymentFailure>,
+    series: UnorderedMap<String, MarketSeries>,
+    series_markets: LookupMap<String, Vec<String>>,
+    series_counter: u64,
+    creator_bond: Balance,
+    approved_quotes: UnorderedSet<AccountId>,
+    approved_underlyings: UnorderedMap<AccountId, Vec<AccountId>>,
+    param_limits: ParamLimits,
+    claim_token_metadata: ClaimTokenMetadataConfig,
+    position_token: Option<AccountId>,
+    default_token_backend: TokenBackend,
 }
 
 #[near]
@@ -75,228 +359,1871 @@ impl ForwardFactory {
             markets: UnorderedMap::new(b"m"),
             markets_by_creator: UnorderedMap::new(b"c"),
             all_market_keys: UnorderedSet::new(b"k"),
-            market_code: Vec::new(),
-            long_token_code: Vec::new(),
-            short_token_code: Vec::new(),
+            market_keys_by_id: LookupMap::new(b"x"),
+            index_by_underlying: TreeMap::new(b"iu".to_vec()),
+            index_by_quote: TreeMap::new(b"iq".to_vec()),
+            index_by_maturity: TreeMap::new(b"im".to_vec()),
+            index_by_status: TreeMap::new(b"is".to_vec()),
+            code_uploads: UnorderedMap::new(b"u"),
+            code_blobs: UnorderedMap::new(b"b"),
+            code_releases: UnorderedMap::new(b"r"),
+            default_release: None,
+            scheduled_upgrades: UnorderedMap::new(b"g"),
+            upgrade_veto_window_secs: DEFAULT_UPGRADE_VETO_WINDOW_SECS,
             paused: false,
             deploy_counter: 0,
+            deployment_failures: Vector::new(b"f"),
+            series: UnorderedMap::new(b"s"),
+            series_markets: LookupMap::new(b"h"),
+            series_counter: 0,
+            creator_bond: 0,
+            approved_quotes: UnorderedSet::new(b"q"),
+            approved_underlyings: UnorderedMap::new(b"l"),
+            param_limits: ParamLimits {
+                min_fee_bps: 0,
+                max_fee_bps: DEFAULT_MAX_FEE_BPS,
+                min_bound_width_bps: DEFAULT_MIN_BOUND_WIDTH_BPS,
+                max_bound_width_bps: DEFAULT_MAX_BOUND_WIDTH_BPS,
+                min_time_to_maturity_secs: DEFAULT_MIN_TIME_TO_MATURITY_SECS,
+                max_time_to_maturity_secs: DEFAULT_MAX_TIME_TO_MATURITY_SECS,
+            },
+            claim_token_metadata: ClaimTokenMetadataConfig::default(),
+            position_token: None,
+            default_token_backend: TokenBackend::Nep141,
         }
     }
 
-    pub fn set_contract_codes(
-        &mut self,
-        market_code: Vec<u8>,
-        long_token_code: Vec<u8>,
-        short_token_code: Vec<u8>,
-    ) {
+    pub fn start_code_upload(&mut self, code_hash: Base58CryptoHash, size: u64) {
         self.assert_owner();
-        self.market_code = market_code;
-        self.long_token_code = long_token_code;
-        self.short_token_code = short_token_code;
-        env::log_str("Contract codes updated");
+        let hash: CryptoHash = code_hash.into();
+        require!(size > 0, "Code size must be positive");
+        require!(self.code_blobs.get(&hash).is_none(), "Code already uploaded");
+        require!(self.code_uploads.get(&hash).is_none(), "Upload already in progress");
+        
+        self.code_uploads.insert(&hash, &CodeUpload {
+            size,
+            received: 0,
+            chunks: 0,
+        });
+        env::log_str(&format!("Code upload started: {} bytes", size));
     }
 
-    #[payable]
-    pub fn deploy_market(&mut self, params: MarketParams) -> Promise {
-        require!(!self.paused, "Factory is paused");
-        require!(!self.market_code.is_empty(), "Market code not set");
-        require!(!self.long_token_code.is_empty(), "Token codes not set");
-        
-        let deposit = env::attached_deposit();
+    pub fn upload_code_chunk(&mut self, code_hash: Base58CryptoHash, chunk: Base64VecU8) {
+        self.assert_owner();
+        let hash: CryptoHash = code_hash.into();
+        let mut upload = self.code_uploads.get(&hash).expect("Upload not started");
+        require!(!chunk.0.is_empty(), "Empty chunk");
         require!(
-            deposit >= MARKET_STORAGE + 2 * TOKEN_STORAGE,
-            "Insufficient deposit for deployment"
+            upload.received + chunk.0.len() as u64 <= upload.size,
+            "Chunk exceeds declared code size"
         );
         
-        let market_key = self.compute_market_key(&params);
-        require!(!self.markets.contains_key(&market_key), "Market already exists");
-        
-        let creator = env::predecessor_account_id();
-        self.deploy_counter += 1;
-        
-        let market_id = AccountId::new_unchecked(format!(
-            "market-{}.{}",
-            self.deploy_counter,
-            env::current_account_id()
-        ));
-        
-        let long_token_id = AccountId::new_unchecked(format!(
-            "long-{}.{}",
-            self.deploy_counter,
-            env::current_account_id()
-        ));
+        env::storage_write(&Self::code_chunk_key(&hash, upload.chunks), &chunk.0);
+        upload.received += chunk.0.len() as u64;
+        upload.chunks += 1;
+        self.code_uploads.insert(&hash, &upload);
+    }
+
+    pub fn finalize_code_upload(&mut self, code_hash: Base58CryptoHash) -> CodeBlob {
+        self.assert_owner();
+        let hash: CryptoHash = code_hash.clone().into();
+        let upload = self.code_uploads.get(&hash).expect("Upload not started");
+        require!(upload.received == upload.size, "Upload incomplete");
         
-        let short_token_id = AccountId::new_unchecked(format!(
-            "short-{}.{}",
-            self.deploy_counter,
-            env::current_account_id()
-        ));
+        let mut code = Vec::with_capacity(upload.size as usize);
+        for index in 0..upload.chunks {
+            let key = Self::code_chunk_key(&hash, index);
+            code.extend(env::storage_read(&key).expect("Missing code chunk"));
+            env::storage_remove(&key);
+        }
+        require!(env::sha256(&code) == hash.to_vec(), "Code hash mismatch");
         
-        let long_name = format!("LONG-{}", params.underlying);
-        let short_name = format!("SHORT-{}", params.underlying);
-        let decimals = 24u8;
+        env::storage_write(&Self::code_blob_key(&hash), &code);
+        self.code_uploads.remove(&hash);
         
-        Promise::new(long_token_id.clone())
-            .create_account()
-            .transfer(TOKEN_STORAGE)
-            .deploy_contract(self.long_token_code.clone())
-            .function_call(
-                "new".to_string(),
-                near_sdk::serde_json::json!({
-                    "market": market_id,
-                    "name": long_name,
-                    "symbol": "LONG",
-                    "decimals": decimals
-                }).to_string().into_bytes(),
-                0,
-                Gas::from_tgas(30),
-            )
-            .then(
-                Promise::new(short_token_id.clone())
-                    .create_account()
-                    .transfer(TOKEN_STORAGE)
-                    .deploy_contract(self.short_token_code.clone())
-                    .function_call(
-                        "new".to_string(),
-                        near_sdk::serde_json::json!({
-                            "market": market_id,
-                            "name": short_name,
-                            "symbol": "SHORT",
-                            "decimals": decimals
-                        }).to_string().into_bytes(),
-                        0,
-                        Gas::from_tgas(30),
-                    )
-            )
-            .then(
-                Promise::new(market_id.clone())
-                    .create_account()
-                    .transfer(MARKET_STORAGE)
-                    .deploy_contract(self.market_code.clone())
-                    .function_call(
-                        "new".to_string(),
-                        near_sdk::serde_json::json!({
-                            "params": params,
-                            "long_token": long_token_id,
-                            "short_token": short_token_id,
-                            "oracle": self.oracle,
-                            "fee_collector": self.fee_collector,
-                            "owner": self.owner,
-                            "guardian": self.guardian
-                        }).to_string().into_bytes(),
-                        0,
-                        Gas::from_tgas(30),
-                    )
-            )
-            .then(
-                Self::ext(env::current_account_id())
-                    .with_static_gas(CALLBACK_GAS)
-                    .on_market_deployed(
-                        market_key.clone(),
-                        MarketInfo {
-                            market_id,
-                            long_token: long_token_id,
-                            short_token: short_token_id,
-                            params,
-                            created_at: env::block_timestamp(),
-                            creator,
-                        }
-                    )
-            )
+        let blob = CodeBlob {
+            code_hash,
+            size: upload.size,
+            uploaded_at: env::block_timestamp(),
+        };
+        self.code_blobs.insert(&hash, &blob);
+        env::log_str(&format!("Code uploaded: {} bytes", upload.size));
+        blob
     }
 
-    #[private]
-    pub fn on_market_deployed(&mut self, market_key: String, market_info: MarketInfo) {
-        self.markets.insert(&market_key, &market_info);
-        self.all_market_keys.insert(&market_key);
-        
-        let mut creator_markets = self.markets_by_creator
-            .get(&market_info.creator)
-            .unwrap_or_else(Vec::new);
-        creator_markets.push(market_key.clone());
-        self.markets_by_creator.insert(&market_info.creator, &creator_markets);
-        
-        env::log_str(&format!(
-            "Market deployed: {} at {}",
-            market_key, market_info.market_id
-        ));
+    pub fn cancel_code_upload(&mut self, code_hash: Base58CryptoHash) {
+        self.assert_owner();
+        let hash: CryptoHash = code_hash.into();
+        let upload = self.code_uploads.remove(&hash).expect("Upload not started");
+        for index in 0..upload.chunks {
+            env::storage_remove(&Self::code_chunk_key(&hash, index));
+        }
+        env::log_str("Code upload cancelled");
     }
 
-    pub fn get_market(&self, market_key: String) -> Option<MarketInfo> {
-        self.markets.get(&market_key)
+    pub fn publish_release(
+        &mut self,
+        version: String,
+        market_code_hash: Base58CryptoHash,
+        claim_token_code_hash: Base58CryptoHash,
+        release_notes: String,
+    ) {
+        self.assert_owner();
+        Self::assert_semver(&version);
+        require!(self.code_releases.get(&version).is_none(), "Release already exists");
+        for code_hash in [&market_code_hash, &claim_token_code_hash] {
+            let hash: CryptoHash = code_hash.clone().into();
+            require!(self.code_blobs.get(&hash).is_some(), "Code not uploaded");
+        }
+        
+        self.code_releases.insert(&version, &CodeRelease {
+            version: version.clone(),
+            market_code_hash,
+            claim_token_code_hash,
+            release_notes,
+            published_at: env::block_timestamp(),
+        });
+        if self.default_release.is_none() {
+            self.default_release = Some(version.clone());
+        }
+        env::log_str(&format!("Release {} published", version));
     }
 
-    pub fn get_market_by_params(&self, params: MarketParams) -> Option<MarketInfo> {
-        let key = self.compute_market_key(&params);
-        self.markets.get(&key)
+    pub fn set_default_release(&mut self, version: String) {
+        self.assert_owner();
+        require!(self.code_releases.get(&version).is_some(), "Release not found");
+        env::log_str(&format!("Default release set to {}", version));
+        self.default_release = Some(version);
     }
 
-    pub fn get_markets_by_creator(&self, creator: AccountId) -> Vec<MarketInfo> {
-        self.markets_by_creator
-            .get(&creator)
-            .unwrap_or_else(Vec::new)
-            .iter()
-            .filter_map(|key| self.markets.get(key))
-            .collect()
+    pub fn set_upgrade_veto_window(&mut self, veto_window_secs: u64) {
+        self.assert_owner();
+        self.upgrade_veto_window_secs = veto_window_secs;
+        env::log_str(&format!("Upgrade veto window set to {}s", veto_window_secs));
     }
 
-    pub fn get_all_markets(&self, from_index: u64, limit: u64) -> Vec<MarketInfo> {
-        let keys: Vec<String> = self.all_market_keys.iter().collect();
-        keys.iter()
-            .skip(from_index as usize)
-            .take(limit as usize)
-            .filter_map(|key| self.markets.get(key))
-            .collect()
+    pub fn schedule_market_upgrade(&mut self, version: String) -> ScheduledUpgrade {
+        self.assert_owner();
+        require!(self.code_releases.get(&version).is_some(), "Release not found");
+        if let Some(existing) = self.scheduled_upgrades.get(&version) {
+            require!(!existing.vetoed, "Upgrade was vetoed; the guardian must lift the veto first");
+        }
+        
+        let now = env::block_timestamp();
+        let upgrade = ScheduledUpgrade {
+            version: version.clone(),
+            scheduled_at: now,
+            executable_at: now + self.upgrade_veto_window_secs * 1_000_000_000,
+            vetoed: false,
+        };
+        self.scheduled_upgrades.insert(&version, &upgrade);
+        env::log_str(&format!(
+            "Market upgrade to {} scheduled, executable at {}",
+            version, upgrade.executable_at
+        ));
+        upgrade
     }
 
-    pub fn get_market_count(&self) -> u64 {
-        self.all_market_keys.len()
+    pub fn veto_market_upgrade(&mut self, version: String) {
+        require!(env::predecessor_account_id() == self.guardian, "Only guardian can veto upgrades");
+        let mut upgrade = self.scheduled_upgrades.get(&version).expect("Upgrade not scheduled");
+        require!(env::block_timestamp() < upgrade.executable_at, "Veto window has passed");
+        
+        upgrade.vetoed = true;
+        self.scheduled_upgrades.insert(&version, &upgrade);
+        env::log_str(&format!("Market upgrade to {} vetoed", version));
     }
 
-    pub fn set_paused(&mut self, paused: bool) {
-        require!(
-            env::predecessor_account_id() == self.guardian || env::predecessor_account_id() == self.owner,
-            "Not authorized"
-        );
-        self.paused = paused;
-        env::log_str(&format!("Factory paused: {}", paused));
+    /// Clears a veto so the owner can schedule the version again; the
+    /// cleared entry itself stays unexecutable until it is rescheduled.
+    pub fn lift_upgrade_veto(&mut self, version: String) {
+        require!(env::predecessor_account_id() == self.guardian, "Only guardian can lift upgrade vetoes");
+        let upgrade = self.scheduled_upgrades.get(&version).expect("Upgrade not scheduled");
+        require!(upgrade.vetoed, "Upgrade is not vetoed");
+        
+        self.scheduled_upgrades.remove(&version);
+        env::log_str(&format!("Veto on market upgrade to {} lifted", version));
     }
 
-    pub fn update_oracle(&mut self, oracle: AccountId) {
+    /// Upgrade receipts are paid for out of this call's gas, so a batch stops
+    /// at the first market that no longer fits and reports where to resume.
+    /// Archived markets have torn down their tokens and are skipped.
+    pub fn upgrade_markets(&mut self, version: String, from_index: u64, limit: u64) -> UpgradeBatch {
         self.assert_owner();
-        self.oracle = oracle;
-        env::log_str(&format!("Oracle updated to {}", self.oracle));
+        let upgrade = self.scheduled_upgrades.get(&version).expect("Upgrade not scheduled");
+        require!(!upgrade.vetoed, "Upgrade was vetoed");
+        require!(env::block_timestamp() >= upgrade.executable_at, "Veto window still open");
+        
+        let release = self.code_releases.get(&version).expect("Release not found");
+        let market_code = self.read_code(&release.market_code_hash);
+        let claim_token_code = self.read_code(&release.claim_token_code_hash);
+        
+        let mut dispatched = 0;
+        let mut next_index = None;
+        for (offset, market_key) in self.all_market_keys.iter().skip(from_index as usize).take(limit as usize).enumerate() {
+            let info = match self.markets.get(&market_key) {
+                Some(info) if info.code_version != version && info.status != MarketStatus::Archived => info,
+                _ => continue,
+            };
+            
+            let contracts = match info.token_backend {
+                TokenBackend::Nep141 => 3,
+                TokenBackend::Nep245 => 1,
+            };
+            let required = Gas::from_gas(
+                UPGRADE_GAS.as_gas() * contracts + ON_UPGRADED_GAS.as_gas() + UPGRADE_BATCH_RESERVE_GAS.as_gas()
+            );
+            if env::prepaid_gas().saturating_sub(env::used_gas()) < required {
+                next_index = Some(from_index + offset as u64);
+                break;
+            }
+            
+            // Position-token markets share one contract, upgraded through
+            // `upgrade_position_token`, so only the market is redeployed.
+            let upgrade = Promise::new(info.market_id)
+                .function_call("upgrade".to_string(), market_code.clone(), NearToken::from_yoctonear(0), UPGRADE_GAS);
+            let upgrade = match info.token_backend {
+                TokenBackend::Nep141 => upgrade
+                    .and(
+                        Promise::new(info.long_token)
+                            .function_call("upgrade".to_string(), claim_token_code.clone(), NearToken::from_yoctonear(0), UPGRADE_GAS)
+                    )
+                    .and(
+                        Promise::new(info.short_token)
+                            .function_call("upgrade".to_string(), claim_token_code.clone(), NearToken::from_yoctonear(0), UPGRADE_GAS)
+                    ),
+                TokenBackend::Nep245 => upgrade,
+            };
+            upgrade
+                .then(
+                    Self::ext(env::current_account_id())
+                        .with_static_gas(ON_UPGRADED_GAS)
+                        .on_market_upgraded(market_key, version.clone())
+                );
+            dispatched += 1;
+        }
+        
+        env::log_str(&format!("Dispatched {} market upgrades to {}", dispatched, version));
+        UpgradeBatch { dispatched, next_index }
     }
 
-    pub fn update_fee_collector(&mut self, fee_collector: AccountId) {
-        self.assert_owner();
-        self.fee_collector = fee_collector;
-        env::log_str(&format!("Fee collector updated to {}", self.fee_collector));
+    #[private]
+    pub fn on_series_account_torn_down(&mut self, series_id: String, storage: U128) -> bool {
+        let torn_down = matches!(env::promise_result(0), PromiseResult::Successful(_));
+        if torn_down {
+            if let Some(mut series) = self.series.get(&series_id) {
+                series.balance = U128(series.balance.0 + storage.0);
+                self.series.insert(&series_id, &series);
+            }
+        } else {
+            env::log_str(&format!("Teardown for series {} failed, {} not returned", series_id, storage.0));
+        }
+        torn_down
     }
 
-    pub fn update_guardian(&mut self, guardian: AccountId) {
+    #[private]
+    pub fn on_market_upgraded(&mut self, market_key: String, version: String) -> bool {
+        let upgraded = (0..env::promise_results_count())
+            .all(|index| matches!(env::promise_result(index), PromiseResult::Successful(_)));
+        
+        if upgraded {
+            if let Some(mut info) = self.markets.get(&market_key) {
+                info.code_version = version.clone();
+                self.markets.insert(&market_key, &info);
+            }
+            env::log_str(&format!("Market {} upgraded to {}", market_key, version));
+        } else {
+            env::log_str(&format!("Market {} upgrade to {} failed", market_key, version));
+        }
+        upgraded
+    }
+
+    pub fn propagate_config(&mut self, from_index: u64, limit: u64) -> u64 {
         self.assert_owner();
-        self.guardian = guardian;
-        env::log_str(&format!("Guardian updated to {}", self.guardian));
+        let config = self.current_config();
+        
+        // Archived markets stay listed but no longer take config updates.
+        let mut dispatched = 0;
+        for market_key in self.all_market_keys.iter().skip(from_index as usize).take(limit as usize) {
+            let info = match self.markets.get(&market_key) {
+                Some(info) if info.config != config && info.status != MarketStatus::Archived => info,
+                _ => continue,
+            };
+            
+            let fee_collector_changed = info.config.fee_collector != config.fee_collector;
+            let update = ext_market::ext(info.market_id.clone())
+                .with_static_gas(UPDATE_CONFIG_GAS)
+                .update_config(
+                    Some(config.oracle.clone()).filter(|oracle| *oracle != info.config.oracle),
+                    Some(config.fee_collector.clone()).filter(|_| fee_collector_changed),
+                    Some(config.guardian.clone()).filter(|guardian| *guardian != info.config.guardian),
+                );
+            let update = if fee_collector_changed {
+                update.and(
+                    ext_fee_collector::ext(config.fee_collector.clone())
+                        .with_static_gas(FEE_COLLECTOR_GAS)
+                        .authorize_market(info.market_id.clone())
+                )
+            } else {
+                update
+            };
+            let update = if fee_collector_changed && info.series_id.is_none() && !info.bond_slashed {
+                update.and(
+                    ext_fee_collector::ext(config.fee_collector.clone())
+                        .with_static_gas(FEE_COLLECTOR_GAS)
+                        .set_market_creator(info.market_id.clone(), Some(info.creator.clone()))
+                )
+            } else {
+                update
+            };
+            
+            update.then(
+                Self::ext(env::current_account_id())
+                    .with_static_gas(CALLBACK_GAS)
+                    .on_config_propagated(market_key, config.clone())
+            );
+            dispatched += 1;
+        }
+        
+        env::log_str(&format!("Dispatched config updates to {} markets", dispatched));
+        dispatched
     }
 
-    fn compute_market_key(&self, params: &MarketParams) -> String {
-        format!(
-            "{}:{}:{}:{}:{}:{}",
-            params.underlying,
-            params.quote,
-            params.maturity,
-            params.strike_k.0,
-            params.lower_bound_l.0,
-            params.upper_bound_u.0
-        )
+    #[private]
+    pub fn on_config_propagated(&mut self, market_key: String, config: MarketConfig) -> bool {
+        let updated = (0..env::promise_results_count())
+            .all(|index| matches!(env::promise_result(index), PromiseResult::Successful(_)));
+        
+        if updated {
+            if let Some(mut info) = self.markets.get(&market_key) {
+                info.config = config;
+                self.markets.insert(&market_key, &info);
+            }
+            env::log_str(&format!("Market {} config updated", market_key));
+        } else {
+            env::log_str(&format!("Market {} config update failed", market_key));
+        }
+        updated
     }
 
-    fn assert_owner(&self) {
-        assert_eq!(
-            env::predecessor_account_id(),
-            self.owner,
-            "Only owner can call this method"
+    #[payable]
+    pub fn deploy_market(
+        &mut self,
+        params: MarketParams,
+        version: Option<String>,
+        token_backend: Option<TokenBackend>,
+    ) -> Promise {
+        require!(!self.paused, "Factory is paused");
+        let release = self.resolve_release(version);
+        let token_backend = token_backend.unwrap_or(self.default_token_backend);
+        
+        let deposit = env::attached_deposit().as_yoctonear();
+        require!(
+            deposit >= Self::deploy_storage(token_backend) + self.creator_bond,
+            "Insufficient deposit for deployment and creator bond"
         );
+        
+        if let Err(reason) = self.validate_market(&params) {
+            env::panic_str(&reason);
+        }
+        
+        let bond = self.creator_bond;
+        self.internal_deploy_market(params, release, token_backend, env::predecessor_account_id(), deposit, bond, None)
     }
-}
\ No newline at end of file
+
+    #[payable]
+    pub fn deploy_market_relative(
+        &mut self,
+        params: RelativeMarketParams,
+        version: Option<String>,
+        token_backend: Option<TokenBackend>,
+    ) -> Promise {
+        require!(!self.paused, "Factory is paused");
+        let release = self.resolve_release(version);
+        let token_backend = token_backend.unwrap_or(self.default_token_backend);
+        
+        let deposit = env::attached_deposit().as_yoctonear();
+        require!(
+            deposit >= Self::deploy_storage(token_backend) + self.creator_bond,
+            "Insufficient deposit for deployment and creator bond"
+        );
+        require!(params.tick_size.0 > 0, "Tick size must be positive");
+        require!(
+            params.lower_offset_bps > 0
+                && (params.lower_offset_bps as u128) < MAX_BPS
+                && params.upper_offset_bps > 0,
+            "Invalid bound offsets"
+        );
+        if let Err(reason) = self.validate_listing(
+            &params.underlying,
+            &params.quote,
+            params.maturity,
+            [params.mint_fee_bps, params.settle_fee_bps, params.redeem_fee_bps],
+        ) {
+            env::panic_str(&reason);
+        }
+        
+        ext_oracle::ext(self.oracle.clone())
+            .with_static_gas(ORACLE_QUERY_GAS)
+            .get_price(params.underlying.clone(), params.quote.clone())
+            .and(
+                ext_oracle::ext(self.oracle.clone())
+                    .with_static_gas(ORACLE_QUERY_GAS)
+                    .get_oracle_config(params.underlying.clone(), params.quote.clone())
+            )
+            .then(
+                Self::ext(env::current_account_id())
+                    .with_static_gas(ON_ORACLE_PRICE_GAS)
+                    .on_oracle_price(
+                        params,
+                        release.version,
+                        token_backend,
+                        env::predecessor_account_id(),
+                        U128(deposit),
+                        U128(self.creator_bond),
+                    )
+            )
+    }
+
+    #[private]
+    pub fn on_oracle_price(
+        &mut self,
+        params: RelativeMarketParams,
+        version: String,
+        token_backend: TokenBackend,
+        creator: AccountId,
+        deposit: U128,
+        bond: U128,
+    ) -> PromiseOrValue<bool> {
+        let configured = match env::promise_result(1) {
+            PromiseResult::Successful(value) => near_sdk::serde_json::from_slice::<Option<near_sdk::serde_json::Value>>(&value)
+                .ok()
+                .flatten()
+                .is_some(),
+            _ => false,
+        };
+        let price = match env::promise_result(0) {
+            PromiseResult::Successful(value) => near_sdk::serde_json::from_slice::<Option<PriceData>>(&value)
+                .ok()
+                .flatten(),
+            _ => None,
+        };
+        
+        // The deposit is already held by the factory, so every refusal below
+        // refunds it instead of panicking.
+        let market_params = if self.paused {
+            Err("factory is paused")
+        } else if !configured {
+            Err("oracle not configured for pair")
+        } else {
+            price
+                .ok_or("oracle price is stale")
+                .and_then(|price| {
+                    Self::normalize_price(price.price.0, price.decimals).ok_or("oracle price out of range")
+                })
+                .and_then(|spot| {
+                    Self::relative_bounds(spot, &params).ok_or("price too low for tick grid")
+                })
+                .map(|(strike_k, lower_bound_l, upper_bound_u)| MarketParams {
+                    underlying: params.underlying.clone(),
+                    quote: params.quote.clone(),
+                    maturity: params.maturity,
+                    strike_k: U128(strike_k),
+                    lower_bound_l: U128(lower_bound_l),
+                    upper_bound_u: U128(upper_bound_u),
+                    mint_fee_bps: params.mint_fee_bps,
+                    settle_fee_bps: params.settle_fee_bps,
+                    redeem_fee_bps: params.redeem_fee_bps,
+                    caps: params.caps.clone(),
+                })
+        }
+        .and_then(|market_params| {
+            if self.markets.get(&self.compute_market_key(&market_params)).is_some() {
+                Err("market already exists")
+            } else {
+                Ok(market_params)
+            }
+        })
+        .map_err(|reason| reason.to_string())
+        .and_then(|market_params| self.validate_market(&market_params).map(|_| market_params));
+        
+        match market_params {
+            Ok(market_params) => {
+                env::log_str(&format!(
+                    "Relative market bounds: K={} L={} U={}",
+                    market_params.strike_k.0, market_params.lower_bound_l.0, market_params.upper_bound_u.0
+                ));
+                let release = self.code_releases.get(&version).expect("Release not found");
+                PromiseOrValue::Promise(self.internal_deploy_market(
+                    market_params,
+                    release,
+                    token_backend,
+                    creator,
+                    deposit.0,
+                    bond.0,
+                    None,
+                ))
+            }
+            Err(reason) => {
+                Promise::new(creator).transfer(NearToken::from_yoctonear(deposit.0));
+                env::log_str(&format!(
+                    "Relative market for {}/{} refused: {}, refunded {}",
+                    params.underlying, params.quote, reason, deposit.0
+                ));
+                PromiseOrValue::Value(false)
+            }
+        }
+    }
+
+    pub fn create_series(
+        &mut self,
+        underlying: AccountId,
+        quote: AccountId,
+        first_maturity: u64,
+        cadence_secs: u64,
+        roll_lead_secs: u64,
+        strike_k: U128,
+        lower_width_bps: u16,
+        upper_width_bps: u16,
+        mint_fee_bps: u16,
+        settle_fee_bps: u16,
+        redeem_fee_bps: u16,
+        version: Option<String>,
+    ) -> String {
+        self.assert_owner();
+        require!(
+            cadence_secs >= MIN_SERIES_CADENCE_SECS,
+            format!("Cadence must be at least {}s", MIN_SERIES_CADENCE_SECS)
+        );
+        require!(roll_lead_secs < cadence_secs, "Roll lead must be shorter than the cadence");
+        require!(first_maturity > env::block_timestamp(), "First maturity must be in the future");
+        require!(strike_k.0 > 0, "Strike must be positive");
+        require!(
+            lower_width_bps > 0 && (lower_width_bps as u128) < MAX_BPS && upper_width_bps > 0,
+            "Invalid bound width"
+        );
+        if let Some(version) = &version {
+            require!(self.code_releases.get(version).is_some(), "Release not found");
+        }
+        
+        self.series_counter += 1;
+        let series_id = format!("series-{}", self.series_counter);
+        let series = MarketSeries {
+            series_id: series_id.clone(),
+            underlying,
+            quote,
+            cadence_secs,
+            roll_lead_secs,
+            next_maturity: first_maturity,
+            strike_k,
+            lower_width_bps,
+            upper_width_bps,
+            mint_fee_bps,
+            settle_fee_bps,
+            redeem_fee_bps,
+            version,
+            creator: env::predecessor_account_id(),
+            balance: U128(0),
+            latest_market: None,
+            roll_pending: false,
+            active: true,
+        };
+        if let Err(reason) = self.validate_market(&Self::series_params(&series)) {
+            env::panic_str(&reason);
+        }
+        
+        self.series.insert(&series_id, &series);
+        env::log_str(&format!("Series {} created", series_id));
+        series_id
+    }
+
+    #[payable]
+    pub fn fund_series(&mut self, series_id: String) -> U128 {
+        let mut series = self.series.get(&series_id).expect("Series not found");
+        series.balance = U128(series.balance.0 + env::attached_deposit().as_yoctonear());
+        self.series.insert(&series_id, &series);
+        env::log_str(&format!("Series {} funded with {}", series_id, env::attached_deposit().as_yoctonear()));
+        series.balance
+    }
+
+    pub fn withdraw_series_funds(&mut self, series_id: String, amount: U128) -> Promise {
+        self.assert_owner();
+        let mut series = self.series.get(&series_id).expect("Series not found");
+        require!(!series.roll_pending, "Roll in progress");
+        require!(amount.0 <= series.balance.0, "Insufficient series balance");
+        
+        series.balance = U128(series.balance.0 - amount.0);
+        self.series.insert(&series_id, &series);
+        env::log_str(&format!("Withdrew {} from series {}", amount.0, series_id));
+        Promise::new(self.owner.clone()).transfer(NearToken::from_yoctonear(amount.0))
+    }
+
+    pub fn set_series_active(&mut self, series_id: String, active: bool) {
+        self.assert_owner();
+        let mut series = self.series.get(&series_id).expect("Series not found");
+        series.active = active;
+        self.series.insert(&series_id, &series);
+        env::log_str(&format!("Series {} active: {}", series_id, active));
+    }
+
+    pub fn roll_series(&mut self, series_id: String) -> Promise {
+        require!(!self.paused, "Factory is paused");
+        let mut series = self.series.get(&series_id).expect("Series not found");
+        require!(series.active, "Series is inactive");
+        require!(!series.roll_pending, "Roll already in progress");
+        
+        // Maturities that passed without a roll are skipped rather than
+        // deployed already expired.
+        let now = env::block_timestamp();
+        let cadence = series.cadence_secs * 1_000_000_000;
+        if series.next_maturity <= now {
+            let missed = (now - series.next_maturity) / cadence + 1;
+            series.next_maturity += missed * cadence;
+        }
+        require!(
+            now + series.roll_lead_secs * 1_000_000_000 >= series.next_maturity,
+            "Series roll not due"
+        );
+        
+        let token_backend = self.default_token_backend;
+        let cost = Self::deploy_storage(token_backend);
+        require!(series.balance.0 >= cost, "Insufficient series balance");
+        
+        let release = self.resolve_release(series.version.clone());
+        let params = Self::series_params(&series);
+        if let Err(reason) = self.validate_market(&params) {
+            env::panic_str(&reason);
+        }
+        series.balance = U128(series.balance.0 - cost);
+        series.roll_pending = true;
+        self.series.insert(&series_id, &series);
+        
+        env::log_str(&format!("Rolling series {} to maturity {}", series_id, params.maturity));
+        self.internal_deploy_market(params, release, token_backend, series.creator, cost, 0, Some(series_id))
+    }
+
+    #[private]
+    pub fn on_market_deployed(
+        &mut self,
+        market_key: String,
+        market_info: MarketInfo,
+        deposit: U128,
+    ) -> bool {
+        let accounts = match market_info.token_backend {
+            TokenBackend::Nep141 => vec![
+                (&market_info.long_token, TOKEN_STORAGE),
+                (&market_info.short_token, TOKEN_STORAGE),
+                (&market_info.market_id, MARKET_STORAGE),
+            ],
+            TokenBackend::Nep245 => vec![(&market_info.market_id, MARKET_STORAGE)],
+        };
+        let succeeded: Vec<bool> = (0..env::promise_results_count())
+            .map(|index| matches!(env::promise_result(index), PromiseResult::Successful(_)))
+            .collect();
+        
+        if succeeded.iter().all(|ok| *ok) {
+            self.markets.insert(&market_key, &market_info);
+            self.all_market_keys.insert(&market_key);
+            
+            let mut creator_markets = self.markets_by_creator
+                .get(&market_info.creator)
+                .unwrap_or_else(Vec::new);
+            creator_markets.push(market_key.clone());
+            self.markets_by_creator.insert(&market_info.creator, &creator_markets);
+            self.internal_index_market(&market_key, &market_info);
+            
+            if let Some(series_id) = &market_info.series_id {
+                self.internal_record_series_roll(series_id, &market_key, market_info.params.maturity);
+            }
+            
+            ext_fee_collector::ext(self.fee_collector.clone())
+                .with_static_gas(FEE_COLLECTOR_GAS)
+                .authorize_market(market_info.market_id.clone());
+            if market_info.series_id.is_none() {
+                ext_fee_collector::ext(self.fee_collector.clone())
+                    .with_static_gas(FEE_COLLECTOR_GAS)
+                    .set_market_creator(market_info.market_id.clone(), Some(market_info.creator.clone()));
+            }
+            
+            let excess = deposit.0 - Self::deploy_storage(market_info.token_backend) - market_info.bond.0;
+            if excess > 0 {
+                Promise::new(market_info.creator.clone()).transfer(NearToken::from_yoctonear(excess));
+            }
+            
+            env::log_str(&format!(
+                "Market deployed: {} at {}",
+                market_key, market_info.market_id
+            ));
+            return true;
+        }
+        
+        // Each failed step was rolled back atomically and its storage transfer
+        // returned to the factory. Accounts that were created are torn down and
+        // hand their remaining balance back to the creator directly; a series
+        // paid for them, so theirs comes back to the factory and is credited to
+        // the series balance once the account is gone.
+        let mut failed_steps = Vec::new();
+        let mut retained: Balance = 0;
+        for ((account_id, storage), ok) in accounts.iter().zip(succeeded.iter()) {
+            if *ok {
+                retained += storage;
+                match &market_info.series_id {
+                    Some(series_id) => {
+                        ext_teardown::ext((*account_id).clone())
+                            .with_static_gas(TEARDOWN_GAS)
+                            .teardown(env::current_account_id())
+                            .then(
+                                Self::ext(env::current_account_id())
+                                    .with_static_gas(ON_TEARDOWN_GAS)
+                                    .on_series_account_torn_down(series_id.clone(), U128(*storage))
+                            );
+                    }
+                    None => {
+                        ext_teardown::ext((*account_id).clone())
+                            .with_static_gas(TEARDOWN_GAS)
+                            .teardown(market_info.creator.clone());
+                    }
+                }
+            } else {
+                failed_steps.push(account_id.to_string());
+            }
+        }
+        // The last result of a position-token deploy is the token registration.
+        if market_info.token_backend == TokenBackend::Nep245 {
+            if succeeded[accounts.len()] {
+                ext_position_token::ext(market_info.long_token.clone())
+                    .with_static_gas(FEE_COLLECTOR_GAS)
+                    .archive_market(market_info.market_id.clone());
+            } else {
+                failed_steps.push(format!("{} registration", market_info.long_token));
+            }
+        }
+        
+        let refunded = deposit.0 - retained;
+        // A series keeps its refund for the next roll attempt.
+        match market_info.series_id.as_ref().and_then(|series_id| self.series.get(series_id)) {
+            Some(mut series) => {
+                series.balance = U128(series.balance.0 + refunded);
+                series.roll_pending = false;
+                self.series.insert(&series.series_id.clone(), &series);
+            }
+            None if refunded > 0 => {
+                Promise::new(market_info.creator.clone()).transfer(NearToken::from_yoctonear(refunded));
+            }
+            None => {}
+        }
+        
+        self.deployment_failures.push(&DeploymentFailure {
+            market_key: market_key.clone(),
+            market_id: market_info.market_id.clone(),
+            creator: market_info.creator.clone(),
+            failed_steps,
+            refunded: U128(refunded),
+            failed_at: env::block_timestamp(),
+        });
+        
+        env::log_str(&format!(
+            "Market deployment failed: {} at {}, refunded {}",
+            market_key, market_info.market_id, refunded
+        ));
+        false
+    }
+
+    pub fn approve_quote(&mut self, quote: AccountId) {
+        self.assert_owner();
+        self.approved_quotes.insert(&quote);
+        env::log_str(&format!("Quote token {} approved", quote));
+    }
+
+    pub fn revoke_quote(&mut self, quote: AccountId) {
+        self.assert_owner();
+        require!(self.approved_quotes.remove(&quote), "Quote token not approved");
+        env::log_str(&format!("Quote token {} revoked", quote));
+    }
+
+    pub fn approve_underlying(&mut self, underlying: AccountId, quote: AccountId) -> Promise {
+        self.assert_owner();
+        require!(self.approved_quotes.contains(&quote), "Quote token not approved");
+        
+        ext_oracle::ext(self.oracle.clone())
+            .with_static_gas(ORACLE_QUERY_GAS)
+            .get_oracle_config(underlying.clone(), quote.clone())
+            .then(
+                Self::ext(env::current_account_id())
+                    .with_static_gas(CALLBACK_GAS)
+                    .on_underlying_checked(underlying, quote)
+            )
+    }
+
+    #[private]
+    pub fn on_underlying_checked(&mut self, underlying: AccountId, quote: AccountId) -> bool {
+        let configured = match env::promise_result(0) {
+            PromiseResult::Successful(value) => near_sdk::serde_json::from_slice::<Option<near_sdk::serde_json::Value>>(&value)
+                .ok()
+                .flatten()
+                .is_some(),
+            _ => false,
+        };
+        if !configured {
+            env::log_str(&format!("Underlying {} not approved: no oracle config for {}/{}", underlying, underlying, quote));
+            return false;
+        }
+        
+        let mut quotes = self.approved_underlyings.get(&underlying).unwrap_or_else(Vec::new);
+        if !quotes.contains(&quote) {
+            quotes.push(quote.clone());
+            self.approved_underlyings.insert(&underlying, &quotes);
+        }
+        env::log_str(&format!("Underlying {} approved against {}", underlying, quote));
+        true
+    }
+
+    pub fn revoke_underlying(&mut self, underlying: AccountId, quote: Option<AccountId>) {
+        self.assert_owner();
+        let mut quotes = self.approved_underlyings.get(&underlying).expect("Underlying not approved");
+        match quote {
+            Some(quote) => {
+                quotes.retain(|approved| *approved != quote);
+                env::log_str(&format!("Underlying {} revoked against {}", underlying, quote));
+            }
+            None => {
+                quotes.clear();
+                env::log_str(&format!("Underlying {} revoked", underlying));
+            }
+        }
+        if quotes.is_empty() {
+            self.approved_underlyings.remove(&underlying);
+        } else {
+            self.approved_underlyings.insert(&underlying, &quotes);
+        }
+    }
+
+    pub fn set_param_limits(&mut self, limits: ParamLimits) {
+        self.assert_owner();
+        require!(limits.min_fee_bps <= limits.max_fee_bps, "Invalid fee range");
+        require!((limits.max_fee_bps as u128) < MAX_BPS, "Max fee must be below 100%");
+        require!(limits.min_bound_width_bps <= limits.max_bound_width_bps, "Invalid bound width range");
+        require!(
+            limits.min_time_to_maturity_secs <= limits.max_time_to_maturity_secs,
+            "Invalid time to maturity range"
+        );
+        self.param_limits = limits;
+        env::log_str("Parameter limits updated");
+    }
+
+    pub fn set_claim_token_metadata(&mut self, metadata: ClaimTokenMetadataConfig) {
+        self.assert_owner();
+        self.claim_token_metadata = metadata;
+        env::log_str("Claim token metadata updated");
+    }
+
+    pub fn set_position_token(&mut self, position_token: AccountId) {
+        self.assert_owner();
+        env::log_str(&format!("Position token set to {}", position_token));
+        self.position_token = Some(position_token);
+    }
+
+    /// The shared position token accepts code only from this factory, so its
+    /// upgrades go through here using uploaded code.
+    pub fn upgrade_position_token(&mut self, code_hash: Base58CryptoHash) -> Promise {
+        self.assert_owner();
+        let position_token = self.position_token.clone().expect("Position token not configured");
+        let code = self.read_code(&code_hash);
+        env::log_str(&format!("Upgrading position token {}", position_token));
+        Promise::new(position_token)
+            .function_call("upgrade".to_string(), code, NearToken::from_yoctonear(0), UPGRADE_GAS)
+    }
+
+    pub fn set_default_token_backend(&mut self, token_backend: TokenBackend) {
+        self.assert_owner();
+        require!(
+            token_backend == TokenBackend::Nep141 || self.position_token.is_some(),
+            "Position token not configured"
+        );
+        self.default_token_backend = token_backend;
+        env::log_str("Default token backend updated");
+    }
+
+    pub fn set_creator_bond(&mut self, bond: U128) {
+        self.assert_owner();
+        self.creator_bond = bond.0;
+        env::log_str(&format!("Creator bond set to {}", bond.0));
+    }
+
+    pub fn slash_creator_bond(&mut self, market_key: String) -> Promise {
+        require!(env::predecessor_account_id() == self.guardian, "Only guardian can slash bonds");
+        let mut info = self.markets.get(&market_key).expect("Market not found");
+        require!(info.bond.0 > 0, "No bond to slash");
+        
+        let bond = info.bond.0;
+        info.bond = U128(0);
+        info.bond_slashed = true;
+        self.markets.insert(&market_key, &info);
+        
+        ext_fee_collector::ext(info.config.fee_collector.clone())
+            .with_static_gas(FEE_COLLECTOR_GAS)
+            .set_market_creator(info.market_id.clone(), None);
+        
+        env::log_str(&format!("Creator bond of {} slashed for market {}", bond, market_key));
+        Promise::new(self.owner.clone()).transfer(NearToken::from_yoctonear(bond))
+    }
+
+    pub fn claim_creator_bond(&mut self, market_key: String) -> Promise {
+        let mut info = self.markets.get(&market_key).expect("Market not found");
+        require!(env::predecessor_account_id() == info.creator, "Only creator can claim the bond");
+        require!(info.status == MarketStatus::Archived, "Market not archived");
+        require!(info.bond.0 > 0, "No bond to claim");
+        
+        let bond = info.bond.0;
+        info.bond = U128(0);
+        self.markets.insert(&market_key, &info);
+        
+        env::log_str(&format!("Creator bond of {} refunded for market {}", bond, market_key));
+        Promise::new(info.creator).transfer(NearToken::from_yoctonear(bond))
+    }
+
+    pub fn snapshot_market_tokens(&mut self, market_key: String) -> Promise {
+        self.assert_owner();
+        let info = self.markets.get(&market_key).expect("Market not found");
+        require!(info.token_backend == TokenBackend::Nep141, "Only claim-token markets support snapshots");
+        require!(info.status != MarketStatus::Archived, "Market is archived");
+        
+        env::log_str(&format!("Snapshotting claim tokens of market {}", market_key));
+        ext_claim_token::ext(info.long_token)
+            .with_static_gas(SNAPSHOT_GAS)
+            .snapshot()
+            .and(
+                ext_claim_token::ext(info.short_token)
+                    .with_static_gas(SNAPSHOT_GAS)
+                    .snapshot()
+            )
+    }
+
+    pub fn report_market_settled(&mut self) {
+        let market_id = env::predecessor_account_id();
+        let market_key = self.market_keys_by_id.get(&market_id).expect("Unknown market");
+        let mut info = self.markets.get(&market_key).expect("Market not found");
+        require!(info.status == MarketStatus::Active, "Market already settled");
+        
+        self.internal_set_status(&market_key, &mut info, MarketStatus::Settled);
+        env::log_str(&format!("Market {} settled", market_key));
+    }
+
+    pub fn get_series(&self, series_id: String) -> Option<MarketSeries> {
+        self.series.get(&series_id)
+    }
+
+    pub fn get_all_series(&self, from_index: u64, limit: u64) -> Vec<MarketSeries> {
+        self.series
+            .values()
+            .skip(from_index as usize)
+            .take(limit as usize)
+            .collect()
+    }
+
+    pub fn get_series_markets(&self, series_id: String) -> Vec<MarketInfo> {
+        self.series_markets
+            .get(&series_id)
+            .unwrap_or_else(Vec::new)
+            .iter()
+            .filter_map(|key| self.markets.get(key))
+            .collect()
+    }
+
+    pub fn get_front_month(&self, series_id: String) -> Option<MarketInfo> {
+        let now = env::block_timestamp();
+        self.series_markets
+            .get(&series_id)
+            .unwrap_or_else(Vec::new)
+            .iter()
+            .filter_map(|key| self.markets.get(key))
+            .find(|info| info.params.maturity > now)
+    }
+
+    pub fn is_series_roll_due(&self, series_id: String) -> bool {
+        let series = match self.series.get(&series_id) {
+            Some(series) => series,
+            None => return false,
+        };
+        let now = env::block_timestamp();
+        series.active
+            && !series.roll_pending
+            && (series.next_maturity <= now
+                || now + series.roll_lead_secs * 1_000_000_000 >= series.next_maturity)
+    }
+
+    pub fn report_market_archived(&mut self) {
+        let market_id = env::predecessor_account_id();
+        let market_key = self.market_keys_by_id.get(&market_id).expect("Unknown market");
+        let mut info = self.markets.get(&market_key).expect("Market not found");
+        require!(info.status != MarketStatus::Archived, "Market already archived");
+        
+        self.internal_set_status(&market_key, &mut info, MarketStatus::Archived);
+        
+        // Series storage was paid by the factory, so it comes back here.
+        let beneficiary = if info.series_id.is_some() {
+            env::current_account_id()
+        } else {
+            info.creator.clone()
+        };
+        ext_fee_collector::ext(info.config.fee_collector.clone())
+            .with_static_gas(FEE_COLLECTOR_GAS)
+            .revoke_market(info.market_id.clone());
+        match info.token_backend {
+            TokenBackend::Nep141 => {
+                for token in [&info.long_token, &info.short_token] {
+                    ext_teardown::ext(token.clone())
+                        .with_static_gas(TEARDOWN_GAS)
+                        .archive(beneficiary.clone());
+                }
+            }
+            TokenBackend::Nep245 => {
+                ext_position_token::ext(info.long_token.clone())
+                    .with_static_gas(FEE_COLLECTOR_GAS)
+                    .archive_market(info.market_id.clone());
+            }
+        }
+        ext_teardown::ext(info.market_id.clone())
+            .with_static_gas(TEARDOWN_GAS)
+            .teardown(beneficiary.clone());
+        
+        env::log_str(&format!("Market {} archived, storage returned to {}", market_key, beneficiary));
+    }
+
+    pub fn get_deployment_failures(&self, from_index: u64, limit: u64) -> Vec<DeploymentFailure> {
+        self.deployment_failures
+            .iter()
+            .skip(from_index as usize)
+            .take(limit as usize)
+            .collect()
+    }
+
+    pub fn get_deployment_failure_count(&self) -> u64 {
+        self.deployment_failures.len()
+    }
+
+    pub fn get_code_blob(&self, code_hash: Base58CryptoHash) -> Option<CodeBlob> {
+        let hash: CryptoHash = code_hash.into();
+        self.code_blobs.get(&hash)
+    }
+
+    pub fn get_release(&self, version: String) -> Option<CodeRelease> {
+        self.code_releases.get(&version)
+    }
+
+    pub fn get_releases(&self, from_index: u64, limit: u64) -> Vec<CodeRelease> {
+        self.code_releases
+            .values()
+            .skip(from_index as usize)
+            .take(limit as usize)
+            .collect()
+    }
+
+    pub fn get_default_release(&self) -> Option<CodeRelease> {
+        self.default_release
+            .as_ref()
+            .and_then(|version| self.code_releases.get(version))
+    }
+
+    pub fn get_scheduled_upgrade(&self, version: String) -> Option<ScheduledUpgrade> {
+        self.scheduled_upgrades.get(&version)
+    }
+
+    pub fn get_upgrade_veto_window(&self) -> u64 {
+        self.upgrade_veto_window_secs
+    }
+
+    pub fn get_market_code_version(&self, market_key: String) -> Option<String> {
+        self.markets.get(&market_key).map(|info| info.code_version)
+    }
+
+    pub fn get_market(&self, market_key: String) -> Option<MarketInfo> {
+        self.markets.get(&market_key)
+    }
+
+    pub fn get_market_by_params(&self, params: MarketParams) -> Option<MarketInfo> {
+        let key = self.compute_market_key(&params);
+        self.markets.get(&key)
+    }
+
+    pub fn get_markets_by_creator(&self, creator: AccountId) -> Vec<MarketInfo> {
+        self.markets_by_creator
+            .get(&creator)
+            .unwrap_or_else(Vec::new)
+            .iter()
+            .filter_map(|key| self.markets.get(key))
+            .collect()
+    }
+
+    pub fn get_all_markets(&self, from_index: u64, limit: u64) -> Vec<MarketInfo> {
+        self.all_market_keys
+            .iter()
+            .skip(from_index as usize)
+            .take(limit as usize)
+            .filter_map(|key| self.markets.get(&key))
+            .collect()
+    }
+
+    pub fn get_markets_by_underlying(
+        &self,
+        underlying: AccountId,
+        cursor: Option<String>,
+        limit: u64,
+    ) -> MarketPage {
+        self.page_index(&self.index_by_underlying, &format!("{}|", underlying), None, cursor, limit)
+    }
+
+    pub fn get_markets_by_quote(&self, quote: AccountId, cursor: Option<String>, limit: u64) -> MarketPage {
+        self.page_index(&self.index_by_quote, &format!("{}|", quote), None, cursor, limit)
+    }
+
+    pub fn get_markets_by_maturity_bucket(
+        &self,
+        bucket: u64,
+        cursor: Option<String>,
+        limit: u64,
+    ) -> MarketPage {
+        self.page_index(&self.index_by_maturity, &format!("{:020}|", bucket), None, cursor, limit)
+    }
+
+    pub fn get_maturity_bucket(&self, timestamp: u64) -> u64 {
+        timestamp / MATURITY_BUCKET_NS
+    }
+
+    pub fn get_markets_by_status(
+        &self,
+        status: MarketStatus,
+        cursor: Option<String>,
+        limit: u64,
+    ) -> MarketPage {
+        // Active and matured markets share the "open" range, ordered by maturity,
+        // so the current time splits it into two contiguous pages.
+        let now_key = format!("{}|{:020}|~", Self::status_tag(&MarketStatus::Active), env::block_timestamp());
+        let prefix = format!("{}|", Self::status_tag(&status));
+        match status {
+            MarketStatus::Active => {
+                let cursor = cursor.filter(|cursor| *cursor > now_key).or(Some(now_key));
+                self.page_index(&self.index_by_status, &prefix, None, cursor, limit)
+            }
+            MarketStatus::Matured => {
+                self.page_index(&self.index_by_status, &prefix, Some(now_key), cursor, limit)
+            }
+            _ => self.page_index(&self.index_by_status, &prefix, None, cursor, limit),
+        }
+    }
+
+    pub fn get_market_status(&self, market_key: String) -> Option<MarketStatus> {
+        self.markets.get(&market_key).map(|info| {
+            if info.status == MarketStatus::Active && info.params.maturity <= env::block_timestamp() {
+                MarketStatus::Matured
+            } else {
+                info.status
+            }
+        })
+    }
+
+    pub fn get_approved_quotes(&self) -> Vec<AccountId> {
+        self.approved_quotes.to_vec()
+    }
+
+    pub fn get_approved_underlyings(&self, from_index: u64, limit: u64) -> Vec<UnderlyingListing> {
+        self.approved_underlyings
+            .iter()
+            .skip(from_index as usize)
+            .take(limit as usize)
+            .map(|(underlying, quotes)| UnderlyingListing { underlying, quotes })
+            .collect()
+    }
+
+    pub fn get_param_limits(&self) -> ParamLimits {
+        self.param_limits.clone()
+    }
+
+    pub fn check_market_params(&self, params: MarketParams) -> Option<String> {
+        self.validate_market(&params).err()
+    }
+
+    pub fn get_claim_token_metadata(&self) -> ClaimTokenMetadataConfig {
+        self.claim_token_metadata.clone()
+    }
+
+    pub fn get_position_token(&self) -> Option<AccountId> {
+        self.position_token.clone()
+    }
+
+    pub fn get_default_token_backend(&self) -> TokenBackend {
+        self.default_token_backend
+    }
+
+    pub fn get_creator_bond(&self) -> U128 {
+        U128(self.creator_bond)
+    }
+
+    pub fn get_config(&self) -> MarketConfig {
+        self.current_config()
+    }
+
+    pub fn get_outdated_markets(&self, from_index: u64, limit: u64) -> Vec<MarketInfo> {
+        let config = self.current_config();
+        self.all_market_keys
+            .iter()
+            .skip(from_index as usize)
+            .take(limit as usize)
+            .filter_map(|key| self.markets.get(&key))
+            .filter(|info| info.config != config && info.status != MarketStatus::Archived)
+            .collect()
+    }
+
+    pub fn get_market_count(&self) -> u64 {
+        self.all_market_keys.len()
+    }
+
+    pub fn set_paused(&mut self, paused: bool) {
+        require!(
+            env::predecessor_account_id() == self.guardian || env::predecessor_account_id() == self.owner,
+            "Not authorized"
+        );
+        self.paused = paused;
+        env::log_str(&format!("Factory paused: {}", paused));
+    }
+
+    pub fn update_oracle(&mut self, oracle: AccountId) {
+        self.assert_owner();
+        self.oracle = oracle;
+        env::log_str(&format!("Oracle updated to {}", self.oracle));
+    }
+
+    pub fn update_fee_collector(&mut self, fee_collector: AccountId) {
+        self.assert_owner();
+        self.fee_collector = fee_collector;
+        env::log_str(&format!("Fee collector updated to {}", self.fee_collector));
+    }
+
+    pub fn update_guardian(&mut self, guardian: AccountId) {
+        self.assert_owner();
+        self.guardian = guardian;
+        env::log_str(&format!("Guardian updated to {}", self.guardian));
+    }
+
+    fn compute_market_key(&self, params: &MarketParams) -> String {
+        format!(
+            "{}:{}:{}:{}:{}:{}",
+            params.underlying,
+            params.quote,
+            params.maturity,
+            params.strike_k.0,
+            params.lower_bound_l.0,
+            params.upper_bound_u.0
+        )
+    }
+
+    fn internal_deploy_market(
+        &mut self,
+        params: MarketParams,
+        release: CodeRelease,
+        token_backend: TokenBackend,
+        creator: AccountId,
+        deposit: Balance,
+        bond: Balance,
+        series_id: Option<String>,
+    ) -> Promise {
+        let market_key = self.compute_market_key(&params);
+        require!(self.markets.get(&market_key).is_none(), "Market already exists");
+        
+        self.deploy_counter += 1;
+        
+        let market_id = AccountId::new_unvalidated(format!(
+            "market-{}.{}",
+            self.deploy_counter,
+            env::current_account_id()
+        ));
+        
+        let (long_token_id, short_token_id) = match token_backend {
+            TokenBackend::Nep141 => (
+                AccountId::new_unvalidated(format!("long-{}.{}", self.deploy_counter, env::current_account_id())),
+                AccountId::new_unvalidated(format!("short-{}.{}", self.deploy_counter, env::current_account_id())),
+            ),
+            TokenBackend::Nep245 => {
+                let position_token = self.position_token.clone().expect("Position token not configured");
+                (position_token.clone(), position_token)
+            }
+        };
+        
+        let deploy_market = Promise::new(market_id.clone())
+            .create_account()
+            .transfer(NearToken::from_yoctonear(MARKET_STORAGE))
+            .deploy_contract(self.read_code(&release.market_code_hash))
+            .function_call(
+                "new".to_string(),
+                near_sdk::serde_json::json!({
+                    "params": params,
+                    "long_token": long_token_id,
+                    "short_token": short_token_id,
+                    "oracle": self.oracle,
+                    "fee_collector": self.fee_collector,
+                    "owner": self.owner,
+                    "guardian": self.guardian,
+                    "token_backend": token_backend
+                }).to_string().into_bytes(),
+                NearToken::from_yoctonear(0),
+                Gas::from_tgas(30),
+            );
+        
+        let deployment = match token_backend {
+            TokenBackend::Nep141 => {
+                let claim_token_code = self.read_code(&release.claim_token_code_hash);
+                let deploy_long = Promise::new(long_token_id.clone())
+                    .create_account()
+                    .transfer(NearToken::from_yoctonear(TOKEN_STORAGE))
+                    .deploy_contract(claim_token_code.clone())
+                    .function_call(
+                        "new".to_string(),
+                        self.claim_token_init_args(&market_id, "Long", &params),
+                        NearToken::from_yoctonear(0),
+                        Gas::from_tgas(30),
+                    );
+                
+                let deploy_short = Promise::new(short_token_id.clone())
+                    .create_account()
+                    .transfer(NearToken::from_yoctonear(TOKEN_STORAGE))
+                    .deploy_contract(claim_token_code)
+                    .function_call(
+                        "new".to_string(),
+                        self.claim_token_init_args(&market_id, "Short", &params),
+                        NearToken::from_yoctonear(0),
+                        Gas::from_tgas(30),
+                    );
+                
+                deploy_long.and(deploy_short).and(deploy_market)
+            }
+            TokenBackend::Nep245 => deploy_market.and(
+                ext_position_token::ext(long_token_id.clone())
+                    .with_static_gas(POSITION_REGISTER_GAS)
+                    .register_market(market_id.clone())
+            ),
+        };
+        
+        deployment
+            .then(
+                Self::ext(env::current_account_id())
+                    .with_static_gas(ON_DEPLOYED_GAS)
+                    .on_market_deployed(
+                        market_key,
+                        MarketInfo {
+                            market_id,
+                            long_token: long_token_id,
+                            short_token: short_token_id,
+                            params,
+                            created_at: env::block_timestamp(),
+                            creator,
+                            code_version: release.version,
+                            config: self.current_config(),
+                            status: MarketStatus::Active,
+                            series_id,
+                            bond: U128(bond),
+                            bond_slashed: false,
+                            token_backend,
+                        },
+                        U128(deposit),
+                    )
+            )
+    }
+
+    fn deploy_storage(token_backend: TokenBackend) -> Balance {
+        match token_backend {
+            TokenBackend::Nep141 => MARKET_STORAGE + 2 * TOKEN_STORAGE,
+            TokenBackend::Nep245 => MARKET_STORAGE,
+        }
+    }
+
+    fn claim_token_init_args(&self, market_id: &AccountId, side: &str, params: &MarketParams) -> Vec<u8> {
+        let metadata = &self.claim_token_metadata;
+        let icon = if side == "Long" { &metadata.long_icon } else { &metadata.short_icon };
+        near_sdk::serde_json::json!({
+            "market": market_id,
+            "side": side,
+            "name": format!("{}-{}", side.to_uppercase(), params.underlying),
+            "symbol": side.to_uppercase(),
+            "decimals": 24u8,
+            "terms": {
+                "maturity": params.maturity,
+                "strike_k": params.strike_k,
+                "lower_bound_l": params.lower_bound_l,
+                "upper_bound_u": params.upper_bound_u
+            },
+            "icon": icon,
+            "reference": metadata
+                .reference_base
+                .as_ref()
+                .map(|base| format!("{}/{}", base.trim_end_matches('/'), market_id)),
+            "reference_hash": None::<String>
+        }).to_string().into_bytes()
+    }
+
+    fn internal_record_series_roll(&mut self, series_id: &String, market_key: &String, maturity: u64) {
+        let mut series_markets = self.series_markets.get(series_id).unwrap_or_else(Vec::new);
+        series_markets.push(market_key.clone());
+        self.series_markets.insert(series_id, &series_markets);
+        
+        if let Some(mut series) = self.series.get(series_id) {
+            series.next_maturity = maturity + series.cadence_secs * 1_000_000_000;
+            series.latest_market = Some(market_key.clone());
+            series.roll_pending = false;
+            self.series.insert(series_id, &series);
+        }
+    }
+
+    fn validate_listing(
+        &self,
+        underlying: &AccountId,
+        quote: &AccountId,
+        maturity: u64,
+        fees: [u16; 3],
+    ) -> Result<(), String> {
+        if !self.approved_quotes.contains(quote) {
+            return Err(format!("Quote token {} is not approved", quote));
+        }
+        let quotes = self.approved_underlyings.get(underlying).unwrap_or_else(Vec::new);
+        if !quotes.contains(quote) {
+            return Err(format!("Underlying {} is not approved against {}", underlying, quote));
+        }
+        
+        let limits = &self.param_limits;
+        for (name, fee_bps) in ["Mint", "Settle", "Redeem"].iter().zip(fees.iter()) {
+            if *fee_bps < limits.min_fee_bps || *fee_bps > limits.max_fee_bps {
+                return Err(format!(
+                    "{} fee {} bps is outside [{}, {}]",
+                    name, fee_bps, limits.min_fee_bps, limits.max_fee_bps
+                ));
+            }
+        }
+        
+        let now = env::block_timestamp();
+        if maturity <= now {
+            return Err("Maturity must be in the future".to_string());
+        }
+        let time_to_maturity_secs = (maturity - now) / 1_000_000_000;
+        if time_to_maturity_secs < limits.min_time_to_maturity_secs
+            || time_to_maturity_secs > limits.max_time_to_maturity_secs
+        {
+            return Err(format!(
+                "Time to maturity {}s is outside [{}, {}]",
+                time_to_maturity_secs, limits.min_time_to_maturity_secs, limits.max_time_to_maturity_secs
+            ));
+        }
+        Ok(())
+    }
+
+    fn validate_market(&self, params: &MarketParams) -> Result<(), String> {
+        self.validate_listing(
+            &params.underlying,
+            &params.quote,
+            params.maturity,
+            [params.mint_fee_bps, params.settle_fee_bps, params.redeem_fee_bps],
+        )?;
+        
+        let (strike, lower, upper) = (params.strike_k.0, params.lower_bound_l.0, params.upper_bound_u.0);
+        if !(lower > 0 && lower < strike && strike < upper) {
+            return Err("Bounds must satisfy 0 < L < K < U".to_string());
+        }
+        let width_bps = (upper - lower) * MAX_BPS / strike;
+        let limits = &self.param_limits;
+        if width_bps < limits.min_bound_width_bps as u128 || width_bps > limits.max_bound_width_bps as u128 {
+            return Err(format!(
+                "Bound width {} bps is outside [{}, {}]",
+                width_bps, limits.min_bound_width_bps, limits.max_bound_width_bps
+            ));
+        }
+        if let Some(caps) = &params.caps {
+            let limits = [caps.max_total_collateral, caps.max_mint_per_account, caps.max_mint_per_window];
+            if limits.iter().any(|limit| *limit == Some(U128(0))) {
+                return Err("Caps must be positive".to_string());
+            }
+            if caps.window_secs > MAX_CAP_WINDOW_SECS {
+                return Err("Cap window exceeds 365 days".to_string());
+            }
+        }
+        Ok(())
+    }
+
+    fn normalize_price(price: u128, decimals: u8) -> Option<u128> {
+        if decimals <= PRICE_DECIMALS {
+            10u128.checked_pow((PRICE_DECIMALS - decimals) as u32)?.checked_mul(price)
+        } else {
+            Some(price / 10u128.checked_pow((decimals - PRICE_DECIMALS) as u32)?)
+        }
+    }
+
+    fn relative_bounds(spot: u128, params: &RelativeMarketParams) -> Option<(u128, u128, u128)> {
+        let tick = params.tick_size.0;
+        let strike = (spot + tick / 2) / tick * tick;
+        let lower = strike * (MAX_BPS - params.lower_offset_bps as u128) / MAX_BPS / tick * tick;
+        let upper = (strike * (MAX_BPS + params.upper_offset_bps as u128) / MAX_BPS + tick - 1) / tick * tick;
+        if lower == 0 || lower >= strike || upper <= strike {
+            return None;
+        }
+        Some((strike, lower, upper))
+    }
+
+    fn series_params(series: &MarketSeries) -> MarketParams {
+        let strike = series.strike_k.0;
+        MarketParams {
+            underlying: series.underlying.clone(),
+            quote: series.quote.clone(),
+            maturity: series.next_maturity,
+            strike_k: series.strike_k,
+            lower_bound_l: U128(strike * (MAX_BPS - series.lower_width_bps as u128) / MAX_BPS),
+            upper_bound_u: U128(strike * (MAX_BPS + series.upper_width_bps as u128) / MAX_BPS),
+            mint_fee_bps: series.mint_fee_bps,
+            settle_fee_bps: series.settle_fee_bps,
+            redeem_fee_bps: series.redeem_fee_bps,
+            caps: None,
+        }
+    }
+
+    fn internal_index_market(&mut self, market_key: &String, info: &MarketInfo) {
+        let maturity = info.params.maturity;
+        self.market_keys_by_id.insert(&info.market_id, market_key);
+        self.index_by_underlying.insert(
+            &format!("{}|{:020}|{}", info.params.underlying, maturity, market_key),
+            market_key,
+        );
+        self.index_by_quote.insert(
+            &format!("{}|{:020}|{}", info.params.quote, maturity, market_key),
+            market_key,
+        );
+        self.index_by_maturity.insert(
+            &format!("{:020}|{:020}|{}", maturity / MATURITY_BUCKET_NS, maturity, market_key),
+            market_key,
+        );
+        self.index_by_status.insert(&Self::status_key(&info.status, maturity, market_key), market_key);
+    }
+
+    fn internal_set_status(&mut self, market_key: &String, info: &mut MarketInfo, status: MarketStatus) {
+        let maturity = info.params.maturity;
+        self.index_by_status.remove(&Self::status_key(&info.status, maturity, market_key));
+        self.index_by_status.insert(&Self::status_key(&status, maturity, market_key), market_key);
+        info.status = status;
+        self.markets.insert(market_key, info);
+    }
+
+    fn page_index(
+        &self,
+        index: &TreeMap<String, String>,
+        prefix: &str,
+        end: Option<String>,
+        cursor: Option<String>,
+        limit: u64,
+    ) -> MarketPage {
+        let start = cursor.unwrap_or_else(|| prefix.to_string());
+        let entries: Vec<(String, String)> = index
+            .iter_from(start)
+            .take_while(|(key, _)| key.starts_with(prefix) && end.as_ref().map_or(true, |end| key < end))
+            .take(limit as usize)
+            .collect();
+        
+        let next_cursor = if entries.len() as u64 == limit {
+            entries.last().map(|(key, _)| key.clone())
+        } else {
+            None
+        };
+        MarketPage {
+            markets: entries
+                .iter()
+                .filter_map(|(_, market_key)| self.markets.get(market_key))
+                .collect(),
+            next_cursor,
+        }
+    }
+
+    fn status_key(status: &MarketStatus, maturity: u64, market_key: &String) -> String {
+        format!("{}|{:020}|{}", Self::status_tag(status), maturity, market_key)
+    }
+
+    fn status_tag(status: &MarketStatus) -> &'static str {
+        match status {
+            MarketStatus::Active | MarketStatus::Matured => "open",
+            MarketStatus::Settled => "settled",
+            MarketStatus::Archived => "archived",
+        }
+    }
+
+    fn current_config(&self) -> MarketConfig {
+        MarketConfig {
+            oracle: self.oracle.clone(),
+            fee_collector: self.fee_collector.clone(),
+            guardian: self.guardian.clone(),
+        }
+    }
+
+    fn resolve_release(&self, version: Option<String>) -> CodeRelease {
+        let version = version
+            .or_else(|| self.default_release.clone())
+            .expect("No release published");
+        self.code_releases.get(&version).expect("Release not found")
+    }
+
+    fn read_code(&self, code_hash: &Base58CryptoHash) -> Vec<u8> {
+        let hash: CryptoHash = code_hash.clone().into();
+        env::storage_read(&Self::code_blob_key(&hash)).expect("Code not uploaded")
+    }
+
+    fn code_chunk_key(hash: &CryptoHash, index: u32) -> Vec<u8> {
+        [CODE_CHUNK_PREFIX, hash.as_slice(), &index.to_be_bytes()].concat()
+    }
+
+    fn code_blob_key(hash: &CryptoHash) -> Vec<u8> {
+        [CODE_BLOB_PREFIX, hash.as_slice()].concat()
+    }
+
+    fn assert_semver(version: &str) {
+        let parts: Vec<&str> = version.split('.').collect();
+        require!(
+            parts.len() == 3 && parts.iter().all(|part| part.parse::<u64>().is_ok()),
+            "Version must be semver (major.minor.patch)"
+        );
+    }
+
+    fn assert_owner(&self) {
+        assert_eq!(
+            env::predecessor_account_id(),
+            self.owner,
+            "Only owner can call this method"
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use near_sdk::mock::MockAction;
+    use near_sdk::test_utils::{get_created_receipts, VMContextBuilder};
+    use near_sdk::{testing_env, RuntimeFeesConfig};
+
+    const ONE: u128 = 1_000_000_000_000_000_000_000_000;
+    const MATURITY: u64 = 7 * 86_400 * 1_000_000_000;
+
+    fn account(id: &str) -> AccountId {
+        id.parse().unwrap()
+    }
+
+    fn set_context(predecessor: &str, block_timestamp: u64, promise_results: Vec<PromiseResult>) {
+        let context = VMContextBuilder::new()
+            .current_account_id(account("factory.near"))
+            .predecessor_account_id(account(predecessor))
+            .block_timestamp(block_timestamp)
+            .build();
+        testing_env!(
+            context,
+            near_sdk::test_vm_config(),
+            RuntimeFeesConfig::test(),
+            Default::default(),
+            promise_results
+        );
+    }
+
+    fn set_deposit_context(predecessor: &str, attached_deposit: u128) {
+        let context = VMContextBuilder::new()
+            .current_account_id(account("factory.near"))
+            .predecessor_account_id(account(predecessor))
+            .attached_deposit(NearToken::from_yoctonear(attached_deposit))
+            .build();
+        testing_env!(context);
+    }
+
+    fn setup() -> ForwardFactory {
+        set_context("owner.near", 0, vec![]);
+        ForwardFactory::new(account("owner.near"), account("oracle.near"), account("fees.near"), account("guardian.near"))
+    }
+
+    fn upload_code(factory: &mut ForwardFactory, code: &[u8]) -> Base58CryptoHash {
+        let code_hash = Base58CryptoHash::from(env::sha256_array(code));
+        factory.start_code_upload(code_hash.clone(), code.len() as u64);
+        factory.upload_code_chunk(code_hash.clone(), Base64VecU8::from(code.to_vec()));
+        factory.finalize_code_upload(code_hash.clone());
+        code_hash
+    }
+
+    // Publishes 1.0.0 and lists wrap.near against usdc.near.
+    fn setup_listed() -> ForwardFactory {
+        let mut factory = setup();
+        let market_code_hash = upload_code(&mut factory, b"market");
+        let claim_token_code_hash = upload_code(&mut factory, b"claim-token");
+        factory.publish_release("1.0.0".to_string(), market_code_hash, claim_token_code_hash, String::new());
+        factory.approve_quote(account("usdc.near"));
+
+        let config = PromiseResult::Successful(b"{}".to_vec());
+        set_context("factory.near", 0, vec![config]);
+        assert!(factory.on_underlying_checked(account("wrap.near"), account("usdc.near")));
+        factory
+    }
+
+    #[derive(Deserialize)]
+    #[serde(crate = "near_sdk::serde")]
+    struct DeployedArgs {
+        market_key: String,
+        market_info: MarketInfo,
+        deposit: U128,
+    }
+
+    // The callback a deploy scheduled, run as if every step succeeded.
+    fn complete_deploy(factory: &mut ForwardFactory) {
+        let args = get_created_receipts()
+            .into_iter()
+            .flat_map(|receipt| receipt.actions)
+            .find_map(|action| match action {
+                MockAction::FunctionCallWeight { method_name, args, .. } if method_name == b"on_market_deployed" => {
+                    Some(near_sdk::serde_json::from_slice::<DeployedArgs>(&args).unwrap())
+                }
+                _ => None,
+            })
+            .expect("No deploy callback scheduled");
+
+        let steps = match args.market_info.token_backend {
+            TokenBackend::Nep141 => 3,
+            TokenBackend::Nep245 => 2,
+        };
+        set_context("factory.near", 0, (0..steps).map(|_| PromiseResult::Successful(vec![])).collect());
+        assert!(factory.on_market_deployed(args.market_key, args.market_info, args.deposit));
+    }
+
+    fn default_params() -> MarketParams {
+        MarketParams {
+            underlying: account("wrap.near"),
+            quote: account("usdc.near"),
+            maturity: MATURITY,
+            strike_k: U128(50 * ONE),
+            lower_bound_l: U128(30 * ONE),
+            upper_bound_u: U128(70 * ONE),
+            mint_fee_bps: 30,
+            settle_fee_bps: 50,
+            redeem_fee_bps: 20,
+            caps: None,
+        }
+    }
+
+    fn position_market(factory: &ForwardFactory) -> MarketInfo {
+        MarketInfo {
+            market_id: account("market-1.factory.near"),
+            long_token: account("positions.near"),
+            short_token: account("positions.near"),
+            params: default_params(),
+            created_at: 0,
+            creator: account("creator.near"),
+            code_version: "1.0.0".to_string(),
+            config: factory.current_config(),
+            status: MarketStatus::Active,
+            series_id: None,
+            bond: U128(0),
+            bond_slashed: false,
+            token_backend: TokenBackend::Nep245,
+        }
+    }
+
+    #[test]
+    fn test_failed_deploy_is_torn_down_and_refunded() {
+        let mut factory = setup();
+        let market_info = position_market(&factory);
+
+        // The market deployed but registering it on the position token failed.
+        let results = vec![PromiseResult::Successful(vec![]), PromiseResult::Failed];
+        set_context("factory.near", 0, results);
+        assert!(!factory.on_market_deployed("wrap.near:usdc.near".to_string(), market_info, U128(20 * ONE)));
+
+        assert_eq!(factory.get_market_count(), 0);
+        let failures = factory.get_deployment_failures(0, 10);
+        assert_eq!(failures.len(), 1);
+        assert_eq!(failures[0].failed_steps, vec!["positions.near registration".to_string()]);
+        // The market's 10 NEAR of storage goes back to the creator with its
+        // teardown; the rest of the deposit is refunded by the factory.
+        assert_eq!(failures[0].refunded, U128(10 * ONE));
+
+        let receipts = get_created_receipts();
+        let teardown = receipts.iter().find(|receipt| receipt.receiver_id == account("market-1.factory.near")).unwrap();
+        assert!(teardown.actions.iter().any(|action| matches!(
+            action,
+            MockAction::FunctionCallWeight { method_name, .. } if method_name == b"teardown"
+        )));
+        let refund = receipts.iter().find(|receipt| receipt.receiver_id == account("creator.near")).unwrap();
+        assert!(refund.actions.iter().any(|action| matches!(
+            action,
+            MockAction::Transfer { deposit, .. } if deposit.as_yoctonear() == 10 * ONE
+        )));
+    }
+    #[test]
+    fn test_series_roll() {
+        let mut factory = setup_listed();
+        let cadence_secs = 14 * 86_400u64;
+        set_context("owner.near", 0, vec![]);
+        let series_id = factory.create_series(
+            account("wrap.near"),
+            account("usdc.near"),
+            MATURITY,
+            cadence_secs,
+            8 * 86_400,
+            U128(50 * ONE),
+            2000,
+            10_000,
+            30,
+            50,
+            20,
+            None,
+        );
+
+        set_deposit_context("owner.near", 20 * ONE);
+        factory.fund_series(series_id.clone());
+        set_context("keeper.near", 0, vec![]);
+        let _ = factory.roll_series(series_id.clone());
+        assert!(factory.get_series(series_id.clone()).unwrap().roll_pending);
+        complete_deploy(&mut factory);
+
+        let markets = factory.get_series_markets(series_id.clone());
+        assert_eq!(markets.len(), 1);
+        assert_eq!(markets[0].params.maturity, MATURITY);
+        assert_eq!(markets[0].params.lower_bound_l, U128(40 * ONE));
+        assert_eq!(markets[0].params.upper_bound_u, U128(100 * ONE));
+
+        let series = factory.get_series(series_id).unwrap();
+        assert_eq!(series.next_maturity, MATURITY + cadence_secs * 1_000_000_000);
+        assert_eq!(series.balance, U128(0));
+        assert!(!series.roll_pending);
+    }
+    #[test]
+    fn test_relative_deploy_rounds_to_tick() {
+        let mut factory = setup_listed();
+        let params = RelativeMarketParams {
+            underlying: account("wrap.near"),
+            quote: account("usdc.near"),
+            maturity: MATURITY,
+            lower_offset_bps: 3333,
+            upper_offset_bps: 3333,
+            tick_size: U128(ONE),
+            mint_fee_bps: 30,
+            settle_fee_bps: 50,
+            redeem_fee_bps: 20,
+            caps: None,
+        };
+        let price = PriceData { price: U128(50 * ONE + ONE / 2), timestamp: 0, decimals: 24 };
+        let results = vec![
+            PromiseResult::Successful(near_sdk::serde_json::to_vec(&Some(price)).unwrap()),
+            PromiseResult::Successful(b"{}".to_vec()),
+        ];
+        set_context("factory.near", 0, results);
+        let _ = factory.on_oracle_price(
+            params,
+            "1.0.0".to_string(),
+            TokenBackend::Nep141,
+            account("creator.near"),
+            U128(20 * ONE),
+            U128(0),
+        );
+        complete_deploy(&mut factory);
+
+        let markets = factory.get_all_markets(0, 10);
+        assert_eq!(markets.len(), 1);
+        // K rounds to the nearest tick, L down and U up
+        assert_eq!(markets[0].params.strike_k, U128(51 * ONE));
+        assert_eq!(markets[0].params.lower_bound_l, U128(34 * ONE));
+        assert_eq!(markets[0].params.upper_bound_u, U128(68 * ONE));
+    }
+}
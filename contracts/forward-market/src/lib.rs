@@ -1,14 +1,49 @@
 use near_contract_standards::fungible_token::receiver::FungibleTokenReceiver;
+use near_contract_standards::storage_management::StorageBalance;
 use near_sdk::borsh::{BorshDeserialize, BorshSerialize};
-use near_sdk::collections::UnorderedMap;
-use near_sdk::json_types::U128;
+use near_sdk::collections::{LookupMap, UnorderedMap};
+use near_sdk::json_types::{I128, U128};
 use near_sdk::serde::{Deserialize, Serialize};
-use near_sdk::{env, ext_contract, near, require, AccountId, Balance, Gas, PanicOnDefault, Promise, PromiseResult};
+use near_sdk::{env, ext_contract, near, require, AccountId, Gas, NearToken, PanicOnDefault, Promise, PromiseOrValue, PromiseResult};
+use uint::construct_uint;
+
+construct_uint! {
+    // Room for 24-decimal fixed-point products before dividing back down
+    pub struct U256(4);
+}
+
+type Balance = u128;
 
 const TGAS: u64 = 1_000_000_000_000;
 const FT_TRANSFER_GAS: Gas = Gas::from_tgas(10);
 const DEPLOY_GAS: Gas = Gas::from_tgas(50);
 const CALLBACK_GAS: Gas = Gas::from_tgas(10);
+const FEE_QUERY_GAS: Gas = Gas::from_tgas(5);
+const FEE_TRANSFER_GAS: Gas = Gas::from_tgas(30);
+const MINT_CALLBACK_GAS: Gas = Gas::from_tgas(120);
+const REDEEM_CALLBACK_GAS: Gas = Gas::from_tgas(100);
+const SETTLE_CALLBACK_GAS: Gas = Gas::from_tgas(70);
+const TOKEN_NOTIFY_GAS: Gas = Gas::from_tgas(5);
+const SUPPLY_QUERY_GAS: Gas = Gas::from_tgas(5);
+const REGISTRATION_QUERY_GAS: Gas = Gas::from_tgas(5);
+const ON_SUPPLY_CHECKED_GAS: Gas = Gas::from_tgas(10);
+const ON_SUPPLY_OP_GAS: Gas = Gas::from_tgas(5);
+const FACTORY_REPORT_GAS: Gas = Gas::from_tgas(10);
+const MAX_BPS: u128 = 10_000;
+const MIGRATE_GAS: Gas = Gas::from_tgas(20);
+const SWEEP_GAS: Gas = Gas::from_tgas(50);
+const ON_ARCHIVE_BALANCE_GAS: Gas = Gas::from_tgas(130);
+const ON_DUST_SWEPT_GAS: Gas = Gas::from_tgas(60);
+const ARCHIVE_REPORT_GAS: Gas = Gas::from_tgas(50);
+const CLAIM_PERIOD_SECS: u64 = 180 * 86_400;
+const FACTOR_SCALE: u128 = 1_000_000_000_000_000_000_000_000;
+const MAX_PAYOFF_POINTS: usize = 100;
+const MAX_CAP_WINDOW_SECS: u64 = 365 * 86_400;
+// Attached to a first create_position to register the minter on the position
+// tokens; whatever a token does not need is returned to the minter.
+const DEFAULT_MINT_STORAGE_DEPOSIT: Balance = 10_000_000_000_000_000_000_000;
+const STATE_VERSION: u16 = 1;
+const STATE_VERSION_KEY: &[u8] = b"STATE_VERSION";
 
 #[derive(BorshSerialize, BorshDeserialize, Serialize, Deserialize, Clone)]
 #[serde(crate = "near_sdk::serde")]
@@ -22,6 +57,24 @@ pub struct MarketParams {
     pub mint_fee_bps: u16,
     pub settle_fee_bps: u16,
     pub redeem_fee_bps: u16,
+    // Read once at init and kept in the market's own `caps` field.
+    #[serde(default)]
+    #[borsh(skip)]
+    pub caps: Option<MarketCaps>,
+}
+
+// Unset limits are not enforced. A window of 0 seconds caps minting per block.
+#[derive(BorshSerialize, BorshDeserialize, Serialize, Deserialize, Clone, Default)]
+#[serde(crate = "near_sdk::serde")]
+pub struct MarketCaps {
+    #[serde(default)]
+    pub max_total_collateral: Option<U128>,
+    #[serde(default)]
+    pub max_mint_per_account: Option<U128>,
+    #[serde(default)]
+    pub max_mint_per_window: Option<U128>,
+    #[serde(default)]
+    pub window_secs: u64,
 }
 
 #[derive(BorshSerialize, BorshDeserialize, Serialize, Deserialize, Clone)]
@@ -35,6 +88,110 @@ pub struct MarketState {
     pub short_token_supply: Balance,
     pub paused_mint: bool,
     pub paused_settle: bool,
+    pub insurance_received: Balance,
+    pub is_archived: bool,
+}
+
+#[derive(BorshSerialize, BorshDeserialize)]
+pub struct LegacyMarketState {
+    pub is_settled: bool,
+    pub settlement_price: Option<U128>,
+    pub settlement_factor: Option<U128>,
+    pub total_collateral: Balance,
+    pub long_token_supply: Balance,
+    pub short_token_supply: Balance,
+    pub paused_mint: bool,
+    pub paused_settle: bool,
+}
+
+#[derive(BorshSerialize, BorshDeserialize, Serialize, Deserialize, Clone, Copy, PartialEq)]
+#[serde(crate = "near_sdk::serde")]
+pub enum TokenBackend {
+    Nep141,
+    Nep245,
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(crate = "near_sdk::serde")]
+pub struct MarketConfig {
+    pub oracle: AccountId,
+    pub fee_collector: AccountId,
+    pub owner: AccountId,
+    pub guardian: AccountId,
+}
+
+// Cost basis covers tokens minted by the account; tokens received by
+// transfer carry no basis, so their whole payout counts as realized PnL.
+#[derive(BorshSerialize, BorshDeserialize, Default)]
+pub struct PositionLedger {
+    pub minted_collateral: Balance,
+    pub fees_paid: Balance,
+    pub redeemed_payouts: Balance,
+    pub realized_pnl: i128,
+    pub cost_basis: Balance,
+    pub basis_tokens: Balance,
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(crate = "near_sdk::serde")]
+pub struct AccountPosition {
+    pub account_id: AccountId,
+    pub minted_collateral: U128,
+    pub fees_paid: U128,
+    pub redeemed_payouts: U128,
+    pub realized_pnl: I128,
+    pub cost_basis: U128,
+    pub long_balance: U128,
+    pub short_balance: U128,
+    pub settlement_factor: Option<U128>,
+    pub mark_value: Option<U128>,
+    pub unrealized_pnl: Option<I128>,
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(crate = "near_sdk::serde")]
+pub struct MintQuote {
+    pub amount: U128,
+    /// yoctoNEAR to attach to create_position
+    pub storage_deposit: U128,
+    pub mint_fee: U128,
+    pub tokens_out: U128,
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(crate = "near_sdk::serde")]
+pub struct RedeemQuote {
+    pub settlement_factor: U128,
+    pub gross_payout: U128,
+    pub settle_fee: U128,
+    pub redeem_fee: U128,
+    pub net_payout: U128,
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(crate = "near_sdk::serde")]
+pub struct PayoffPoint {
+    pub price: U128,
+    pub settlement_factor: U128,
+    pub long_payout: U128,
+    pub short_payout: U128,
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(crate = "near_sdk::serde")]
+pub struct SupplyReport {
+    pub long_counter: U128,
+    pub long_token_supply: Option<U128>,
+    pub short_counter: U128,
+    pub short_token_supply: Option<U128>,
+    pub in_sync: bool,
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(crate = "near_sdk::serde")]
+pub struct MintMessage {
+    pub action_id: String,
+    pub referrer: Option<AccountId>,
 }
 
 #[ext_contract(ext_ft)]
@@ -48,12 +205,25 @@ trait FungibleToken {
         msg: String,
     ) -> Promise;
     fn ft_balance_of(&self, account_id: AccountId) -> U128;
+    fn ft_total_supply(&self) -> U128;
 }
 
 #[ext_contract(ext_token)]
 trait ClaimToken {
     fn mint(&mut self, account_id: AccountId, amount: U128);
     fn burn(&mut self, account_id: AccountId, amount: U128);
+    fn mark_settled(&mut self, settlement_factor: U128);
+    fn set_transfer_restriction(&mut self, restricted: bool);
+    fn storage_balance_of(&self, account_id: AccountId) -> Option<StorageBalance>;
+}
+
+#[ext_contract(ext_mt)]
+trait PositionToken {
+    fn mt_mint(&mut self, token_id: String, account_id: AccountId, amount: U128);
+    fn mt_burn(&mut self, token_id: String, account_id: AccountId, amount: U128);
+    fn mt_supply(&self, token_id: String) -> Option<U128>;
+    fn mt_balance_of(&self, account_id: AccountId, token_id: String) -> U128;
+    fn storage_balance_of(&self, account_id: AccountId) -> Option<StorageBalance>;
 }
 
 #[ext_contract(ext_oracle)]
@@ -63,7 +233,15 @@ trait OracleRouter {
 
 #[ext_contract(ext_fee_collector)]
 trait FeeCollector {
-    fn record_fee(&mut self, token: AccountId, amount: Balance);
+    fn request_insurance_draw(&mut self, token: AccountId, amount: U128) -> u64;
+    fn record_volume(&mut self, account: AccountId, token: AccountId, amount: U128);
+    fn get_fee_discount_bps(&self, account: AccountId, token: AccountId) -> u16;
+}
+
+#[ext_contract(ext_factory)]
+trait ForwardFactory {
+    fn report_market_settled(&mut self);
+    fn report_market_archived(&mut self);
 }
 
 #[derive(BorshSerialize, BorshDeserialize, Serialize, Deserialize)]
@@ -87,6 +265,75 @@ pub struct ForwardMarket {
     guardian: AccountId,
     user_deposits: UnorderedMap<AccountId, Balance>,
     pending_actions: UnorderedMap<String, PendingAction>,
+    token_backend: TokenBackend,
+    mint_storage_deposit: Balance,
+    // Replaces user_deposits; entries there are moved over on first write.
+    positions: LookupMap<AccountId, PositionLedger>,
+    caps: MarketCaps,
+    window_id: u64,
+    window_minted: Balance,
+    // NEAR attached to a pending mint, forwarded to the tokens with it
+    action_deposits: LookupMap<String, Balance>,
+    // Mint and burn calls not yet resolved, and all ever dispatched, so a
+    // reconcile can tell whether the token supplies moved under it.
+    supply_ops_in_flight: u64,
+    supply_ops_dispatched: u64,
+    // Numbers pending actions, so each has its own key.
+    action_nonce: u64,
+}
+
+// The layout markets were deployed with before state versioning.
+#[derive(BorshSerialize, BorshDeserialize)]
+pub struct LegacyForwardMarket {
+    params: MarketParams,
+    state: LegacyMarketState,
+    long_token: AccountId,
+    short_token: AccountId,
+    oracle: AccountId,
+    fee_collector: AccountId,
+    owner: AccountId,
+    guardian: AccountId,
+    user_deposits: UnorderedMap<AccountId, Balance>,
+    pending_actions: UnorderedMap<String, PendingAction>,
+}
+
+impl From<LegacyForwardMarket> for ForwardMarket {
+    fn from(old: LegacyForwardMarket) -> Self {
+        Self {
+            params: old.params,
+            state: MarketState {
+                is_settled: old.state.is_settled,
+                settlement_price: old.state.settlement_price,
+                settlement_factor: old.state.settlement_factor,
+                total_collateral: old.state.total_collateral,
+                long_token_supply: old.state.long_token_supply,
+                short_token_supply: old.state.short_token_supply,
+                paused_mint: old.state.paused_mint,
+                paused_settle: old.state.paused_settle,
+                insurance_received: 0,
+                is_archived: false,
+            },
+            long_token: old.long_token,
+            short_token: old.short_token,
+            oracle: old.oracle,
+            fee_collector: old.fee_collector,
+            owner: old.owner,
+            guardian: old.guardian,
+            user_deposits: old.user_deposits,
+            pending_actions: old.pending_actions,
+            token_backend: TokenBackend::Nep141,
+            mint_storage_deposit: DEFAULT_MINT_STORAGE_DEPOSIT,
+            positions: LookupMap::new(b"l"),
+            caps: MarketCaps::default(),
+            window_id: 0,
+            window_minted: 0,
+            action_deposits: LookupMap::new(b"a"),
+            supply_ops_in_flight: 0,
+            supply_ops_dispatched: 0,
+            // Legacy actions are keyed by the block they were created in.
+            action_nonce: env::block_height(),
+        }
+    }
 }
 
 #[derive(BorshSerialize, BorshDeserialize)]
@@ -113,13 +360,17 @@ impl ForwardMarket {
         fee_collector: AccountId,
         owner: AccountId,
         guardian: AccountId,
+        token_backend: Option<TokenBackend>,
     ) -> Self {
         assert!(!env::state_exists(), "Already initialized");
         require!(params.upper_bound_u > params.lower_bound_l, "Invalid bounds");
         require!(params.strike_k >= params.lower_bound_l, "Strike below lower bound");
         require!(params.strike_k <= params.upper_bound_u, "Strike above upper bound");
         require!(params.maturity > env::block_timestamp(), "Maturity in past");
+        let caps = params.caps.clone().unwrap_or_default();
+        Self::validate_caps(&caps);
         
+        Self::write_state_version();
         Self {
             params,
             state: MarketState {
@@ -131,6 +382,8 @@ impl ForwardMarket {
                 short_token_supply: 0,
                 paused_mint: false,
                 paused_settle: false,
+                insurance_received: 0,
+                is_archived: false,
             },
             long_token,
             short_token,
@@ -140,22 +393,55 @@ impl ForwardMarket {
             guardian,
             user_deposits: UnorderedMap::new(b"d"),
             pending_actions: UnorderedMap::new(b"p"),
+            token_backend: token_backend.unwrap_or(TokenBackend::Nep141),
+            mint_storage_deposit: DEFAULT_MINT_STORAGE_DEPOSIT,
+            positions: LookupMap::new(b"l"),
+            caps,
+            window_id: 0,
+            window_minted: 0,
+            action_deposits: LookupMap::new(b"a"),
+            supply_ops_in_flight: 0,
+            supply_ops_dispatched: 0,
+            action_nonce: 0,
         }
     }
 
-    pub fn create_position(&mut self, amount: U128) -> Promise {
+    /// A first mint needs `get_mint_storage_deposit` attached to register the
+    /// account on the position tokens.
+    #[payable]
+    pub fn create_position(&mut self, amount: U128, referrer: Option<AccountId>) -> Promise {
         require!(!self.state.paused_mint, "Minting is paused");
         require!(!self.state.is_settled, "Market is settled");
         require!(amount.0 > 0, "Amount must be positive");
         
         let account = env::predecessor_account_id();
-        let action_id = format!("mint_{}", env::block_index());
+        let storage_deposit = env::attached_deposit().as_yoctonear();
+        let required = self.storage_deposit_for(&account);
+        require!(
+            storage_deposit >= required,
+            format!("Attach {} yoctoNEAR for token storage", required)
+        );
+        self.action_nonce += 1;
+        let action_id = format!("mint_{}", self.action_nonce);
+        env::log_str(&format!("Mint {} pending for {}", action_id, account));
         
         self.pending_actions.insert(&action_id, &PendingAction {
             account: account.clone(),
             amount: amount.0,
             action_type: ActionType::Mint,
         });
+        if storage_deposit > 0 {
+            self.action_deposits.insert(&action_id, &storage_deposit);
+        }
+        
+        let msg = match referrer {
+            Some(referrer) => near_sdk::serde_json::to_string(&MintMessage {
+                action_id,
+                referrer: Some(referrer),
+            })
+            .unwrap(),
+            None => action_id,
+        };
         
         ext_ft::ext(self.params.quote.clone())
             .with_static_gas(FT_TRANSFER_GAS)
@@ -163,46 +449,113 @@ impl ForwardMarket {
                 env::current_account_id(),
                 amount,
                 None,
-                action_id,
+                msg,
             )
     }
 
+    /// Drops a mint whose quote transfer never arrived and returns its NEAR.
+    pub fn cancel_mint(&mut self, action_id: String) {
+        let action = self.pending_actions.get(&action_id).expect("Action not found");
+        require!(action.account == env::predecessor_account_id(), "Not the action's account");
+        require!(matches!(action.action_type, ActionType::Mint), "Not a mint");
+        
+        self.pending_actions.remove(&action_id);
+        let storage_deposit = self.action_deposits.remove(&action_id).unwrap_or(0);
+        self.internal_refund_storage(&action.account, storage_deposit);
+        env::log_str(&format!("Mint {} cancelled", action_id));
+    }
+
     pub fn redeem(&mut self, long_amount: U128, short_amount: U128) -> Promise {
         require!(self.state.is_settled, "Market not settled");
+        require!(!self.state.is_archived, "Market is archived");
         require!(long_amount.0 > 0 || short_amount.0 > 0, "No tokens to redeem");
         
         let account = env::predecessor_account_id();
+        
+        ext_fee_collector::ext(self.fee_collector.clone())
+            .with_static_gas(FEE_QUERY_GAS)
+            .get_fee_discount_bps(account.clone(), self.params.quote.clone())
+            .then(
+                Self::ext(env::current_account_id())
+                    .with_static_gas(REDEEM_CALLBACK_GAS)
+                    .on_redeem_discount(account, long_amount, short_amount)
+            )
+    }
+
+    #[private]
+    pub fn on_redeem_discount(
+        &mut self,
+        account: AccountId,
+        long_amount: U128,
+        short_amount: U128,
+    ) -> Promise {
+        let discount_bps = Self::resolve_discount();
+        self.internal_redeem(account.clone(), &account, long_amount, short_amount, discount_bps)
+    }
+
+    #[private]
+    pub fn on_auto_redeem_discount(&mut self, account: AccountId, long_amount: U128, short_amount: U128) -> U128 {
+        let discount_bps = Self::resolve_discount();
+        self.internal_redeem(account, &env::current_account_id(), long_amount, short_amount, discount_bps);
+        U128(0)
+    }
+
+    // `holder` is the account the claim tokens are burned from: the redeemer,
+    // or the market itself when they were sent here with ft_transfer_call.
+    fn internal_redeem(
+        &mut self,
+        account: AccountId,
+        holder: &AccountId,
+        long_amount: U128,
+        short_amount: U128,
+        discount_bps: u16,
+    ) -> Promise {
         let settlement_factor = self.state.settlement_factor.expect("Settlement factor not set");
         
-        let long_payout = self.calculate_payout(long_amount.0, settlement_factor.0, true);
-        let short_payout = self.calculate_payout(short_amount.0, settlement_factor.0, false);
+        let long_payout = self.settled_payout(long_amount.0, settlement_factor.0, true);
+        let short_payout = self.settled_payout(short_amount.0, settlement_factor.0, false);
         let total_payout = long_payout + short_payout;
         
-        let fee = (total_payout * self.params.redeem_fee_bps as u128) / 10000;
+        let fee = self.calculate_fee(total_payout, self.params.redeem_fee_bps, discount_bps);
         let net_payout = total_payout - fee;
         
-        require!(net_payout <= self.state.total_collateral, "Insufficient collateral");
+        require!(total_payout <= self.state.total_collateral, "Insufficient collateral");
         
         self.state.total_collateral -= total_payout;
+        self.state.long_token_supply -= long_amount.0;
+        self.state.short_token_supply -= short_amount.0;
+        
+        let mut ledger = self.internal_ledger(&account);
+        let redeemed_tokens = long_amount.0 + short_amount.0;
+        let covered_tokens = redeemed_tokens.min(ledger.basis_tokens);
+        let consumed_basis = if covered_tokens == 0 {
+            0
+        } else {
+            mul_div(ledger.cost_basis, covered_tokens, ledger.basis_tokens)
+        };
+        ledger.cost_basis -= consumed_basis;
+        ledger.basis_tokens -= covered_tokens;
+        ledger.fees_paid += fee;
+        ledger.redeemed_payouts += net_payout;
+        ledger.realized_pnl += net_payout as i128 - consumed_basis as i128;
+        self.internal_save_ledger(&account, &ledger);
         
         if long_amount.0 > 0 {
-            ext_token::ext(self.long_token.clone())
-                .with_static_gas(FT_TRANSFER_GAS)
-                .burn(account.clone(), long_amount);
+            self.internal_burn(true, holder, long_amount);
         }
         
         if short_amount.0 > 0 {
-            ext_token::ext(self.short_token.clone())
-                .with_static_gas(FT_TRANSFER_GAS)
-                .burn(account.clone(), short_amount);
+            self.internal_burn(false, holder, short_amount);
         }
         
         if fee > 0 {
-            ext_fee_collector::ext(self.fee_collector.clone())
-                .with_static_gas(FT_TRANSFER_GAS)
-                .record_fee(self.params.quote.clone(), fee);
+            self.internal_send_fee(fee, "fee".to_string());
         }
         
+        ext_fee_collector::ext(self.fee_collector.clone())
+            .with_static_gas(FT_TRANSFER_GAS)
+            .record_volume(account.clone(), self.params.quote.clone(), U128(total_payout));
+        
         ext_ft::ext(self.params.quote.clone())
             .with_static_gas(FT_TRANSFER_GAS)
             .ft_transfer(
@@ -212,6 +565,283 @@ impl ForwardMarket {
             )
     }
 
+    #[private]
+    pub fn on_mint_discount(
+        &mut self,
+        account: AccountId,
+        amount: U128,
+        referrer: Option<AccountId>,
+        storage_deposit: U128,
+    ) -> U128 {
+        if self.state.paused_mint || self.state.is_settled {
+            env::log_str("Mint refunded: market no longer accepts deposits");
+            self.internal_refund_storage(&account, storage_deposit.0);
+            return amount;
+        }
+        
+        // Other mints may have landed since ft_on_transfer checked.
+        if let Err(reason) = self.check_caps(&account, amount.0) {
+            env::log_str(&format!("Mint refunded: {}", reason));
+            self.internal_refund_storage(&account, storage_deposit.0);
+            return amount;
+        }
+        // The ledger cannot tell whether the account has since unregistered
+        // from the tokens, so their answer decides whether storage is owed.
+        if !Self::resolve_registered() && storage_deposit.0 < self.mint_storage_deposit {
+            env::log_str(&format!(
+                "Mint refunded: {} is not registered on the tokens; attach {} yoctoNEAR",
+                account, self.mint_storage_deposit
+            ));
+            self.internal_refund_storage(&account, storage_deposit.0);
+            return amount;
+        }
+        let window_id = self.current_window_id();
+        if window_id != self.window_id {
+            self.window_id = window_id;
+            self.window_minted = 0;
+        }
+        
+        let discount_bps = Self::resolve_discount();
+        let fee = self.calculate_fee(amount.0, self.params.mint_fee_bps, discount_bps);
+        let net_amount = amount.0 - fee;
+        self.window_minted += net_amount;
+        
+        self.state.total_collateral += net_amount;
+        self.state.long_token_supply += net_amount;
+        self.state.short_token_supply += net_amount;
+        
+        let mut ledger = self.internal_ledger(&account);
+        ledger.minted_collateral += net_amount;
+        ledger.fees_paid += fee;
+        ledger.cost_basis += amount.0;
+        ledger.basis_tokens += 2 * net_amount;
+        self.internal_save_ledger(&account, &ledger);
+        
+        // The minter's NEAR pays for its storage on the tokens; each side
+        // returns what it does not need.
+        let long_deposit = storage_deposit.0 / 2;
+        self.internal_mint(true, &account, U128(net_amount), long_deposit);
+        self.internal_mint(false, &account, U128(net_amount), storage_deposit.0 - long_deposit);
+        
+        if fee > 0 {
+            let msg = match referrer {
+                Some(referrer) => near_sdk::serde_json::json!({
+                    "referrer": referrer,
+                    "account": account,
+                })
+                .to_string(),
+                None => "fee".to_string(),
+            };
+            self.internal_send_fee(fee, msg);
+        }
+        
+        ext_fee_collector::ext(self.fee_collector.clone())
+            .with_static_gas(FT_TRANSFER_GAS)
+            .record_volume(account, self.params.quote.clone(), amount);
+        
+        U128(0)
+    }
+
+    // Mint and burn promises are never checked, so the counters can drift
+    // from the tokens' own supplies.
+    pub fn check_supply(&mut self) -> Promise {
+        self.supply_query().then(
+            Self::ext(env::current_account_id())
+                .with_static_gas(ON_SUPPLY_CHECKED_GAS)
+                .on_supply_checked(false, self.supply_ops_dispatched)
+        )
+    }
+
+    pub fn reconcile_supply(&mut self) -> Promise {
+        require!(env::predecessor_account_id() == self.guardian, "Only guardian can call this method");
+        require!(self.supply_ops_in_flight == 0, "Mints or burns are in flight");
+        self.supply_query().then(
+            Self::ext(env::current_account_id())
+                .with_static_gas(ON_SUPPLY_CHECKED_GAS)
+                .on_supply_checked(true, self.supply_ops_dispatched)
+        )
+    }
+
+    // A resolution callback that ran out of gas never decrements the count,
+    // which would block reconcile_supply for good.
+    pub fn reset_supply_ops_in_flight(&mut self) {
+        require!(env::predecessor_account_id() == self.owner, "Only owner can call this method");
+        env::log_str(&format!("Supply ops in flight reset from {}", self.supply_ops_in_flight));
+        self.supply_ops_in_flight = 0;
+    }
+
+    #[private]
+    pub fn on_supply_checked(&mut self, reconcile: bool, ops_dispatched: u64) -> SupplyReport {
+        let supplies: Vec<Option<u128>> = (0..2)
+            .map(|index| match env::promise_result(index) {
+                PromiseResult::Successful(value) => near_sdk::serde_json::from_slice::<Option<U128>>(&value)
+                    .ok()
+                    .flatten()
+                    .map(|supply| supply.0),
+                _ => None,
+            })
+            .collect();
+        let (long_supply, short_supply) = (supplies[0], supplies[1]);
+        let in_sync = long_supply == Some(self.state.long_token_supply)
+            && short_supply == Some(self.state.short_token_supply);
+        
+        if !in_sync {
+            env::log_str(&format!(
+                "Supply mismatch: long {} vs {:?}, short {} vs {:?}",
+                self.state.long_token_supply, long_supply, self.state.short_token_supply, short_supply
+            ));
+            // Supplies read while a mint or burn was landing would overwrite
+            // the counters with a stale value.
+            let settled = self.supply_ops_in_flight == 0 && self.supply_ops_dispatched == ops_dispatched;
+            if reconcile && !settled {
+                env::log_str("Reconcile skipped: mints or burns were in flight");
+            } else if let (true, Some(long_supply), Some(short_supply)) = (reconcile, long_supply, short_supply) {
+                self.state.long_token_supply = long_supply;
+                self.state.short_token_supply = short_supply;
+                env::log_str("Supply counters resynced to token supplies");
+            }
+        }
+        
+        SupplyReport {
+            long_counter: U128(self.state.long_token_supply),
+            long_token_supply: long_supply.map(U128),
+            short_counter: U128(self.state.short_token_supply),
+            short_token_supply: short_supply.map(U128),
+            in_sync,
+        }
+    }
+
+    #[private]
+    pub fn on_supply_op_resolved(&mut self, description: String) -> bool {
+        self.supply_ops_in_flight = self.supply_ops_in_flight.saturating_sub(1);
+        let resolved = matches!(env::promise_result(0), PromiseResult::Successful(_));
+        if !resolved {
+            env::log_str(&format!("{} failed", description));
+        }
+        resolved
+    }
+
+    fn supply_query(&self) -> Promise {
+        match self.token_backend {
+            TokenBackend::Nep141 => ext_ft::ext(self.long_token.clone())
+                .with_static_gas(SUPPLY_QUERY_GAS)
+                .ft_total_supply()
+                .and(
+                    ext_ft::ext(self.short_token.clone())
+                        .with_static_gas(SUPPLY_QUERY_GAS)
+                        .ft_total_supply()
+                ),
+            TokenBackend::Nep245 => ext_mt::ext(self.long_token.clone())
+                .with_static_gas(SUPPLY_QUERY_GAS)
+                .mt_supply(self.position_token_id(true))
+                .and(
+                    ext_mt::ext(self.short_token.clone())
+                        .with_static_gas(SUPPLY_QUERY_GAS)
+                        .mt_supply(self.position_token_id(false))
+                ),
+        }
+    }
+
+    pub fn request_insurance_draw(&mut self) -> Promise {
+        require!(self.state.is_settled, "Market not settled");
+        require!(!self.state.is_archived, "Market is archived");
+
+        let shortfall = self.get_collateral_shortfall().0;
+        require!(shortfall > 0, "No collateral shortfall");
+
+        ext_fee_collector::ext(self.fee_collector.clone())
+            .with_static_gas(FT_TRANSFER_GAS)
+            .request_insurance_draw(self.params.quote.clone(), U128(shortfall))
+    }
+
+    pub fn archive(&mut self) -> Promise {
+        require!(self.state.is_settled, "Market not settled");
+        require!(!self.state.is_archived, "Market already archived");
+        let redeemed = self.state.long_token_supply == 0 && self.state.short_token_supply == 0;
+        require!(
+            redeemed || env::block_timestamp() >= self.get_claim_deadline(),
+            "Positions outstanding until the claim deadline"
+        );
+        
+        // Blocks redemptions while the sweep is in flight; cleared again if it fails.
+        self.state.is_archived = true;
+        ext_ft::ext(self.params.quote.clone())
+            .with_static_gas(FEE_QUERY_GAS)
+            .ft_balance_of(env::current_account_id())
+            .then(
+                Self::ext(env::current_account_id())
+                    .with_static_gas(ON_ARCHIVE_BALANCE_GAS)
+                    .on_archive_balance()
+            )
+    }
+
+    #[private]
+    pub fn on_archive_balance(&mut self) -> PromiseOrValue<bool> {
+        let balance = match env::promise_result(0) {
+            PromiseResult::Successful(value) => near_sdk::serde_json::from_slice::<U128>(&value).ok(),
+            _ => None,
+        };
+        match balance {
+            None => {
+                self.state.is_archived = false;
+                env::log_str("Archive aborted: could not read quote balance");
+                PromiseOrValue::Value(false)
+            }
+            Some(U128(0)) => {
+                self.internal_finish_archive(0);
+                PromiseOrValue::Value(true)
+            }
+            // Fees already reached the fee collector as they were charged, so
+            // what is left is unclaimed collateral, rounding dust and any fee
+            // transfer it refused. It is swept as dust so it is not credited as
+            // fee revenue a second time.
+            Some(balance) => ext_ft::ext(self.params.quote.clone())
+                .with_static_gas(SWEEP_GAS)
+                .with_attached_deposit(NearToken::from_yoctonear(1))
+                .ft_transfer_call(
+                    self.fee_collector.clone(),
+                    balance,
+                    Some("Market dust".to_string()),
+                    "dust".to_string(),
+                )
+                .then(
+                    Self::ext(env::current_account_id())
+                        .with_static_gas(ON_DUST_SWEPT_GAS)
+                        .on_dust_swept(balance)
+                )
+                .into(),
+        }
+    }
+
+    #[private]
+    pub fn on_dust_swept(&mut self, amount: U128) -> bool {
+        let used = match env::promise_result(0) {
+            PromiseResult::Successful(value) => near_sdk::serde_json::from_slice::<U128>(&value)
+                .map(|used| used.0)
+                .unwrap_or(0),
+            _ => 0,
+        };
+        if used < amount.0 {
+            self.state.is_archived = false;
+            env::log_str(&format!("Archive aborted: swept {} of {}", used, amount.0));
+            return false;
+        }
+        
+        self.internal_finish_archive(used);
+        true
+    }
+
+    fn internal_finish_archive(&mut self, swept: Balance) {
+        self.state.total_collateral = 0;
+        ext_factory::ext(self.factory_account())
+            .with_static_gas(ARCHIVE_REPORT_GAS)
+            .report_market_archived();
+        env::log_str(&format!(
+            "Market archived: swept {} to fee collector, {} long and {} short unredeemed",
+            swept, self.state.long_token_supply, self.state.short_token_supply
+        ));
+    }
+
     pub fn settle(&mut self) -> Promise {
         require!(!self.state.paused_settle, "Settlement is paused");
         require!(!self.state.is_settled, "Already settled");
@@ -222,7 +852,7 @@ impl ForwardMarket {
             .get_price(self.params.underlying.clone(), self.params.quote.clone())
             .then(
                 Self::ext(env::current_account_id())
-                    .with_static_gas(CALLBACK_GAS)
+                    .with_static_gas(SETTLE_CALLBACK_GAS)
                     .on_price_received()
             )
     }
@@ -257,17 +887,121 @@ impl ForwardMarket {
         self.state.settlement_factor = Some(U128(settlement_factor));
         
         if fee > 0 {
-            ext_fee_collector::ext(self.fee_collector.clone())
-                .with_static_gas(FT_TRANSFER_GAS)
-                .record_fee(self.params.quote.clone(), fee);
+            self.internal_send_fee(fee, "fee".to_string());
+        }
+        
+        if self.token_backend == TokenBackend::Nep141 {
+            for token in [&self.long_token, &self.short_token] {
+                ext_token::ext(token.clone())
+                    .with_static_gas(TOKEN_NOTIFY_GAS)
+                    .mark_settled(U128(settlement_factor));
+            }
         }
         
+        ext_factory::ext(self.factory_account())
+            .with_static_gas(FACTORY_REPORT_GAS)
+            .report_market_settled();
+        
         env::log_str(&format!(
             "Market settled: price={}, factor={}",
             price.0, settlement_factor
         ));
     }
 
+    // Fees are paid out to the fee collector as they are charged. A transfer
+    // it refuses comes back here and is swept when the market is archived.
+    fn internal_send_fee(&self, amount: Balance, msg: String) {
+        ext_ft::ext(self.params.quote.clone())
+            .with_static_gas(FEE_TRANSFER_GAS)
+            .with_attached_deposit(NearToken::from_yoctonear(1))
+            .ft_transfer_call(
+                self.fee_collector.clone(),
+                U128(amount),
+                Some("Market fee".to_string()),
+                msg,
+            );
+    }
+
+    fn internal_mint(&mut self, is_long: bool, account: &AccountId, amount: U128, storage_deposit: Balance) {
+        let mint = match self.token_backend {
+            TokenBackend::Nep141 => ext_token::ext(self.side_token(is_long))
+                .with_static_gas(FT_TRANSFER_GAS)
+                .with_attached_deposit(NearToken::from_yoctonear(storage_deposit))
+                .mint(account.clone(), amount),
+            TokenBackend::Nep245 => ext_mt::ext(self.side_token(is_long))
+                .with_static_gas(FT_TRANSFER_GAS)
+                .with_attached_deposit(NearToken::from_yoctonear(storage_deposit))
+                .mt_mint(self.position_token_id(is_long), account.clone(), amount),
+        };
+        self.internal_track_supply_op(mint, format!("Mint of {} to {}", amount.0, account));
+    }
+
+    fn internal_refund_storage(&self, account: &AccountId, storage_deposit: Balance) {
+        if storage_deposit > 0 {
+            Promise::new(account.clone()).transfer(NearToken::from_yoctonear(storage_deposit));
+        }
+    }
+
+    fn internal_burn(&mut self, is_long: bool, account: &AccountId, amount: U128) {
+        let burn = match self.token_backend {
+            TokenBackend::Nep141 => ext_token::ext(self.side_token(is_long))
+                .with_static_gas(FT_TRANSFER_GAS)
+                .burn(account.clone(), amount),
+            TokenBackend::Nep245 => ext_mt::ext(self.side_token(is_long))
+                .with_static_gas(FT_TRANSFER_GAS)
+                .mt_burn(self.position_token_id(is_long), account.clone(), amount),
+        };
+        self.internal_track_supply_op(burn, format!("Burn of {} from {}", amount.0, account));
+    }
+
+    fn internal_track_supply_op(&mut self, op: Promise, description: String) {
+        self.supply_ops_in_flight += 1;
+        self.supply_ops_dispatched += 1;
+        op.then(
+            Self::ext(env::current_account_id())
+                .with_static_gas(ON_SUPPLY_OP_GAS)
+                .on_supply_op_resolved(description)
+        );
+    }
+
+    fn registration_query(&self, account: &AccountId) -> Promise {
+        match self.token_backend {
+            TokenBackend::Nep141 => ext_token::ext(self.long_token.clone())
+                .with_static_gas(REGISTRATION_QUERY_GAS)
+                .storage_balance_of(account.clone())
+                .and(
+                    ext_token::ext(self.short_token.clone())
+                        .with_static_gas(REGISTRATION_QUERY_GAS)
+                        .storage_balance_of(account.clone())
+                ),
+            TokenBackend::Nep245 => ext_mt::ext(self.long_token.clone())
+                .with_static_gas(REGISTRATION_QUERY_GAS)
+                .storage_balance_of(account.clone()),
+        }
+    }
+
+    // Reads the registration_query results that follow the fee discount.
+    fn resolve_registered() -> bool {
+        (1..env::promise_results_count()).all(|index| match env::promise_result(index) {
+            PromiseResult::Successful(value) => {
+                matches!(near_sdk::serde_json::from_slice::<Option<StorageBalance>>(&value), Ok(Some(_)))
+            }
+            _ => false,
+        })
+    }
+
+    fn side_token(&self, is_long: bool) -> AccountId {
+        if is_long {
+            self.long_token.clone()
+        } else {
+            self.short_token.clone()
+        }
+    }
+
+    fn position_token_id(&self, is_long: bool) -> String {
+        format!("{}:{}", env::current_account_id(), if is_long { "long" } else { "short" })
+    }
+
     fn calculate_settlement_factor(&self, price: u128) -> u128 {
         let l = self.params.lower_bound_l.0;
         let u = self.params.upper_bound_u.0;
@@ -275,25 +1009,219 @@ impl ForwardMarket {
         if price <= l {
             0
         } else if price >= u {
-            10_u128.pow(24)
+            FACTOR_SCALE
         } else {
-            ((price - l) * 10_u128.pow(24)) / (u - l)
+            mul_div(price - l, FACTOR_SCALE, u - l)
+        }
+    }
+
+    fn read_state_version() -> u16 {
+        env::storage_read(STATE_VERSION_KEY)
+            .map(|bytes| u16::from_le_bytes(bytes.try_into().expect("Invalid state version")))
+            .unwrap_or(0)
+    }
+
+    fn write_state_version() {
+        env::storage_write(STATE_VERSION_KEY, &STATE_VERSION.to_le_bytes());
+    }
+
+    fn factory_account(&self) -> AccountId {
+        env::current_account_id()
+            .get_parent_account_id()
+            .expect("No factory account")
+            .into()
+    }
+
+    fn assert_factory(&self) {
+        require!(
+            env::predecessor_account_id() == self.factory_account(),
+            "Only factory can call this method"
+        );
+    }
+
+    fn calculate_fee(&self, amount: u128, fee_bps: u16, discount_bps: u16) -> u128 {
+        let discount_bps = (discount_bps as u128).min(MAX_BPS);
+        mul_div(amount, fee_bps as u128 * (MAX_BPS - discount_bps), MAX_BPS * MAX_BPS)
+    }
+
+    fn resolve_discount() -> u16 {
+        match env::promise_result(0) {
+            PromiseResult::Successful(value) => {
+                near_sdk::serde_json::from_slice::<u16>(&value).unwrap_or(0)
+            }
+            _ => 0,
         }
     }
 
     fn calculate_payout(&self, amount: u128, settlement_factor: u128, is_long: bool) -> u128 {
         if is_long {
-            (amount * settlement_factor) / 10_u128.pow(24)
+            mul_div(amount, settlement_factor, FACTOR_SCALE)
         } else {
-            (amount * (10_u128.pow(24) - settlement_factor)) / 10_u128.pow(24)
+            mul_div(amount, FACTOR_SCALE - settlement_factor, FACTOR_SCALE)
         }
     }
 
-    pub fn preview_settlement(&self, hypothetical_price: U128) -> (U128, U128) {
+    // Settlement takes its fee out of the whole pool, so every token is paid
+    // its gross payout less the same share.
+    fn settled_payout(&self, amount: u128, settlement_factor: u128, is_long: bool) -> u128 {
+        mul_div(
+            self.calculate_payout(amount, settlement_factor, is_long),
+            MAX_BPS - self.params.settle_fee_bps as u128,
+            MAX_BPS,
+        )
+    }
+
+    // Gross value of `amount` LONG and SHORT tokens (one unit scaled to 1e24
+    // by default) at the given price, before fees.
+    pub fn preview_settlement(&self, hypothetical_price: U128, amount: Option<U128>) -> (U128, U128) {
         let factor = self.calculate_settlement_factor(hypothetical_price.0);
-        let long_value = (10_u128.pow(24) * factor) / 10_u128.pow(24);
-        let short_value = 10_u128.pow(24) - long_value;
-        (U128(long_value), U128(short_value))
+        let amount = amount.map_or(FACTOR_SCALE, |amount| amount.0);
+        (
+            U128(self.calculate_payout(amount, factor, true)),
+            U128(self.calculate_payout(amount, factor, false)),
+        )
+    }
+
+    // Fee discounts come from the fee collector and are not known to a view,
+    // so quotes use the full fee unless a discount is supplied.
+    pub fn preview_mint(&self, amount: U128, account_id: Option<AccountId>, discount_bps: Option<u16>) -> MintQuote {
+        let storage_deposit = account_id.map_or(self.mint_storage_deposit, |account| self.storage_deposit_for(&account));
+        let mint_fee = self.calculate_fee(amount.0, self.params.mint_fee_bps, discount_bps.unwrap_or(0));
+        MintQuote {
+            amount,
+            storage_deposit: U128(storage_deposit),
+            mint_fee: U128(mint_fee),
+            tokens_out: U128(amount.0 - mint_fee),
+        }
+    }
+
+    // Before settlement the price is required. Either way the tokens are
+    // charged their pro rata share of the settle fee taken from the pool.
+    pub fn preview_redeem(
+        &self,
+        long_amount: U128,
+        short_amount: U128,
+        hypothetical_price: Option<U128>,
+        discount_bps: Option<u16>,
+    ) -> RedeemQuote {
+        let factor = match self.state.settlement_factor {
+            Some(factor) => factor.0,
+            None => {
+                let price = hypothetical_price.expect("Price required before settlement");
+                self.calculate_settlement_factor(price.0)
+            }
+        };
+        self.quote_redeem(long_amount.0, short_amount.0, factor, discount_bps.unwrap_or(0))
+    }
+
+    // Net payout of `amount` tokens on each side across a price grid, after
+    // the settle and redeem fees.
+    pub fn preview_payoff_curve(&self, amount: U128, prices: Vec<U128>) -> Vec<PayoffPoint> {
+        require!(prices.len() <= MAX_PAYOFF_POINTS, "Too many prices");
+        prices
+            .into_iter()
+            .map(|price| {
+                let factor = self.calculate_settlement_factor(price.0);
+                let long = self.quote_redeem(amount.0, 0, factor, 0);
+                let short = self.quote_redeem(0, amount.0, factor, 0);
+                PayoffPoint {
+                    price,
+                    settlement_factor: U128(factor),
+                    long_payout: long.net_payout,
+                    short_payout: short.net_payout,
+                }
+            })
+            .collect()
+    }
+
+    fn storage_deposit_for(&self, account: &AccountId) -> Balance {
+        if !self.positions.contains_key(account) && self.user_deposits.get(account).is_none() {
+            self.mint_storage_deposit
+        } else {
+            0
+        }
+    }
+
+    // Mirrors internal_redeem so a quote matches what a redemption pays.
+    fn quote_redeem(&self, long_amount: u128, short_amount: u128, factor: u128, discount_bps: u16) -> RedeemQuote {
+        let gross_payout = self.calculate_payout(long_amount, factor, true)
+            + self.calculate_payout(short_amount, factor, false);
+        let settled_payout = self.settled_payout(long_amount, factor, true)
+            + self.settled_payout(short_amount, factor, false);
+        let redeem_fee = self.calculate_fee(settled_payout, self.params.redeem_fee_bps, discount_bps);
+        RedeemQuote {
+            settlement_factor: U128(factor),
+            gross_payout: U128(gross_payout),
+            settle_fee: U128(gross_payout - settled_payout),
+            redeem_fee: U128(redeem_fee),
+            net_payout: U128(settled_payout - redeem_fee),
+        }
+    }
+
+    pub fn upgrade(&mut self) -> Promise {
+        self.assert_factory();
+        let code = env::input().expect("No code provided");
+        env::log_str(&format!("Market {} upgrading", env::current_account_id()));
+        Promise::new(env::current_account_id())
+            .deploy_contract(code)
+            .function_call("migrate".to_string(), Vec::new(), NearToken::from_yoctonear(0), MIGRATE_GAS)
+    }
+
+    #[private]
+    #[init(ignore_state)]
+    pub fn migrate() -> Self {
+        let version = Self::read_state_version();
+        require!(version <= STATE_VERSION, "State version is newer than code");
+        let market: Self = match version {
+            STATE_VERSION => env::state_read().expect("Failed to read state"),
+            _ => {
+                let old: LegacyForwardMarket = env::state_read().expect("Failed to read state");
+                old.into()
+            }
+        };
+        Self::write_state_version();
+        env::log_str(&format!(
+            "Market state migrated from version {} to {}",
+            version, STATE_VERSION
+        ));
+        market
+    }
+
+    pub fn get_state_version(&self) -> u16 {
+        Self::read_state_version()
+    }
+
+    pub fn update_config(
+        &mut self,
+        oracle: Option<AccountId>,
+        fee_collector: Option<AccountId>,
+        guardian: Option<AccountId>,
+    ) {
+        self.assert_factory();
+        if let Some(oracle) = oracle {
+            env::log_str(&format!("Oracle updated to {}", oracle));
+            self.oracle = oracle;
+        }
+        if let Some(fee_collector) = fee_collector {
+            env::log_str(&format!("Fee collector updated to {}", fee_collector));
+            self.fee_collector = fee_collector;
+        }
+        if let Some(guardian) = guardian {
+            env::log_str(&format!("Guardian updated to {}", guardian));
+            self.guardian = guardian;
+        }
+    }
+
+    pub fn teardown(&mut self, beneficiary: AccountId) -> Promise {
+        self.assert_factory();
+        require!(self.state.total_collateral == 0, "Market holds collateral");
+        require!(
+            self.state.is_archived
+                || (self.state.long_token_supply == 0 && self.state.short_token_supply == 0),
+            "Market has outstanding positions"
+        );
+        env::log_str(&format!("Market {} torn down", env::current_account_id()));
+        Promise::new(env::current_account_id()).delete_account(beneficiary)
     }
 
     pub fn set_paused(&mut self, pause_mint: bool, pause_settle: bool) {
@@ -305,17 +1233,193 @@ impl ForwardMarket {
         self.state.paused_settle = pause_settle;
     }
 
+    pub fn set_settled_transfer_restriction(&mut self, restricted: bool) {
+        require!(env::predecessor_account_id() == self.owner, "Only owner can call this method");
+        require!(self.token_backend == TokenBackend::Nep141, "Only claim-token markets restrict transfers");
+        for token in [&self.long_token, &self.short_token] {
+            ext_token::ext(token.clone())
+                .with_static_gas(TOKEN_NOTIFY_GAS)
+                .set_transfer_restriction(restricted);
+        }
+    }
+
+    pub fn set_mint_storage_deposit(&mut self, deposit: U128) {
+        require!(env::predecessor_account_id() == self.owner, "Only owner can call this method");
+        self.mint_storage_deposit = deposit.0;
+        env::log_str(&format!("Mint storage deposit set to {}", deposit.0));
+    }
+
+    pub fn get_mint_storage_deposit(&self) -> U128 {
+        U128(self.mint_storage_deposit)
+    }
+
     pub fn get_market_params(&self) -> MarketParams {
-        self.params.clone()
+        let mut params = self.params.clone();
+        params.caps = Some(self.caps.clone());
+        params
+    }
+
+    pub fn set_caps(&mut self, caps: MarketCaps) {
+        require!(env::predecessor_account_id() == self.guardian, "Only guardian can call this method");
+        Self::validate_caps(&caps);
+        self.caps = caps;
+        env::log_str("Market caps updated");
+    }
+
+    pub fn get_caps(&self) -> MarketCaps {
+        self.caps.clone()
+    }
+
+    // Largest deposit the account could mint with right now, or None if uncapped.
+    pub fn get_mint_capacity(&self, account_id: AccountId) -> Option<U128> {
+        let window_minted = if self.current_window_id() == self.window_id { self.window_minted } else { 0 };
+        [
+            self.caps.max_total_collateral.map(|cap| cap.0.saturating_sub(self.state.total_collateral)),
+            self.caps
+                .max_mint_per_account
+                .map(|cap| cap.0.saturating_sub(self.internal_ledger(&account_id).minted_collateral)),
+            self.caps.max_mint_per_window.map(|cap| cap.0.saturating_sub(window_minted)),
+        ]
+        .into_iter()
+        .flatten()
+        .min()
+        .map(U128)
+    }
+
+    fn validate_caps(caps: &MarketCaps) {
+        let limits = [caps.max_total_collateral, caps.max_mint_per_account, caps.max_mint_per_window];
+        require!(!limits.iter().any(|limit| *limit == Some(U128(0))), "Caps must be positive");
+        require!(caps.window_secs <= MAX_CAP_WINDOW_SECS, "Cap window exceeds 365 days");
+    }
+
+    // Every cap counts net collateral. The incoming deposit is checked at its
+    // gross amount, so it is never refunded for exceeding a cap only after fees.
+    fn check_caps(&self, account: &AccountId, amount: Balance) -> Result<(), String> {
+        if let Some(cap) = self.caps.max_total_collateral {
+            if self.state.total_collateral + amount > cap.0 {
+                return Err(format!(
+                    "market collateral cap of {} reached ({} held)",
+                    cap.0, self.state.total_collateral
+                ));
+            }
+        }
+        if let Some(cap) = self.caps.max_mint_per_account {
+            let minted = self.internal_ledger(account).minted_collateral;
+            if minted + amount > cap.0 {
+                return Err(format!("per-account mint cap of {} reached ({} minted)", cap.0, minted));
+            }
+        }
+        if let Some(cap) = self.caps.max_mint_per_window {
+            let minted = if self.current_window_id() == self.window_id { self.window_minted } else { 0 };
+            if minted + amount > cap.0 {
+                return Err(format!("mint cap of {} per window reached ({} minted)", cap.0, minted));
+            }
+        }
+        Ok(())
+    }
+
+    fn current_window_id(&self) -> u64 {
+        if self.caps.window_secs == 0 {
+            env::block_height()
+        } else {
+            env::block_timestamp() / (self.caps.window_secs * 1_000_000_000)
+        }
+    }
+
+    pub fn get_config(&self) -> MarketConfig {
+        MarketConfig {
+            oracle: self.oracle.clone(),
+            fee_collector: self.fee_collector.clone(),
+            owner: self.owner.clone(),
+            guardian: self.guardian.clone(),
+        }
     }
 
     pub fn get_market_state(&self) -> MarketState {
         self.state.clone()
     }
 
+    pub fn get_token_backend(&self) -> TokenBackend {
+        self.token_backend
+    }
+
+    pub fn get_claim_deadline(&self) -> u64 {
+        self.params.maturity + CLAIM_PERIOD_SECS * 1_000_000_000
+    }
+
+    pub fn get_collateral_shortfall(&self) -> U128 {
+        let factor = match self.state.settlement_factor {
+            Some(factor) => factor.0,
+            None => return U128(0),
+        };
+        let owed = self.settled_payout(self.state.long_token_supply, factor, true)
+            + self.settled_payout(self.state.short_token_supply, factor, false);
+        U128(owed.saturating_sub(self.state.total_collateral))
+    }
+
+    // Net collateral the account has minted with, including amounts it has
+    // since redeemed; see get_account_position for the full picture.
     pub fn get_user_deposit(&self, account: AccountId) -> U128 {
-        U128(self.user_deposits.get(&account).unwrap_or(0))
+        U128(self.internal_ledger(&account).minted_collateral)
     }
+
+    // A view: the caller reads the LONG/SHORT balances from the token
+    // contracts and passes them in to be marked.
+    pub fn get_account_position(
+        &self,
+        account_id: AccountId,
+        long_balance: U128,
+        short_balance: U128,
+        hypothetical_price: Option<U128>,
+    ) -> AccountPosition {
+        let (long_balance, short_balance) = (long_balance.0, short_balance.0);
+        let ledger = self.internal_ledger(&account_id);
+        let settlement_factor = self
+            .state
+            .settlement_factor
+            .map(|factor| factor.0)
+            .or_else(|| hypothetical_price.map(|price| self.calculate_settlement_factor(price.0)));
+        let mark_value = settlement_factor.map(|factor| {
+            self.settled_payout(long_balance, factor, true) + self.settled_payout(short_balance, factor, false)
+        });
+        
+        AccountPosition {
+            account_id,
+            minted_collateral: U128(ledger.minted_collateral),
+            fees_paid: U128(ledger.fees_paid),
+            redeemed_payouts: U128(ledger.redeemed_payouts),
+            realized_pnl: I128(ledger.realized_pnl),
+            cost_basis: U128(ledger.cost_basis),
+            long_balance: U128(long_balance),
+            short_balance: U128(short_balance),
+            settlement_factor: settlement_factor.map(U128),
+            mark_value: mark_value.map(U128),
+            unrealized_pnl: mark_value.map(|value| I128(value as i128 - ledger.cost_basis as i128)),
+        }
+    }
+
+    // Accounts that minted before the ledger existed start from their
+    // recorded deposit, with unknown fees.
+    fn internal_ledger(&self, account: &AccountId) -> PositionLedger {
+        self.positions.get(account).unwrap_or_else(|| {
+            let deposit = self.user_deposits.get(account).unwrap_or(0);
+            PositionLedger {
+                minted_collateral: deposit,
+                cost_basis: deposit,
+                basis_tokens: 2 * deposit,
+                ..Default::default()
+            }
+        })
+    }
+
+    fn internal_save_ledger(&mut self, account: &AccountId, ledger: &PositionLedger) {
+        self.positions.insert(account, ledger);
+        self.user_deposits.remove(account);
+    }
+}
+
+fn mul_div(a: u128, b: u128, denominator: u128) -> u128 {
+    (U256::from(a) * U256::from(b) / U256::from(denominator)).as_u128()
 }
 
 #[near]
@@ -325,47 +1429,326 @@ impl FungibleTokenReceiver for ForwardMarket {
         sender_id: AccountId,
         amount: U128,
         msg: String,
-    ) -> U128 {
-        require!(env::predecessor_account_id() == self.params.quote, "Wrong token");
+    ) -> PromiseOrValue<U128> {
+        let token = env::predecessor_account_id();
+        if self.token_backend == TokenBackend::Nep141 && (token == self.long_token || token == self.short_token) {
+            // Claim tokens sent here after settlement are redeemed for the sender.
+            if !self.state.is_settled || self.state.is_archived || amount.0 == 0 {
+                return PromiseOrValue::Value(amount);
+            }
+            let (long_amount, short_amount) = if token == self.long_token {
+                (amount, U128(0))
+            } else {
+                (U128(0), amount)
+            };
+            return ext_fee_collector::ext(self.fee_collector.clone())
+                .with_static_gas(FEE_QUERY_GAS)
+                .get_fee_discount_bps(sender_id.clone(), self.params.quote.clone())
+                .then(
+                    Self::ext(env::current_account_id())
+                        .with_static_gas(REDEEM_CALLBACK_GAS)
+                        .on_auto_redeem_discount(sender_id, long_amount, short_amount)
+                )
+                .into();
+        }
+        require!(token == self.params.quote, "Wrong token");
+        
+        if msg == "insurance" && sender_id == self.fee_collector && !self.state.is_archived {
+            self.state.total_collateral += amount.0;
+            self.state.insurance_received += amount.0;
+            env::log_str(&format!("Insurance draw received: {}", amount.0));
+            return PromiseOrValue::Value(U128(0));
+        }
         
-        if let Some(action) = self.pending_actions.get(&msg) {
+        let mint_msg = near_sdk::serde_json::from_str::<MintMessage>(&msg).unwrap_or(MintMessage {
+            action_id: msg,
+            referrer: None,
+        });
+        let referrer = mint_msg.referrer.filter(|referrer| *referrer != sender_id);
+        
+        if let Some(action) = self.pending_actions.get(&mint_msg.action_id) {
             if action.account == sender_id && action.amount == amount.0 {
                 match action.action_type {
                     ActionType::Mint => {
-                        let fee = (amount.0 * self.params.mint_fee_bps as u128) / 10000;
-                        let net_amount = amount.0 - fee;
-                        
-                        self.state.total_collateral += net_amount;
-                        self.state.long_token_supply += net_amount;
-                        self.state.short_token_supply += net_amount;
-                        
-                        let current = self.user_deposits.get(&sender_id).unwrap_or(0);
-                        self.user_deposits.insert(&sender_id, &(current + net_amount));
-                        
-                        ext_token::ext(self.long_token.clone())
-                            .with_static_gas(FT_TRANSFER_GAS)
-                            .mint(sender_id.clone(), U128(net_amount));
-                        
-                        ext_token::ext(self.short_token.clone())
-                            .with_static_gas(FT_TRANSFER_GAS)
-                            .mint(sender_id.clone(), U128(net_amount));
-                        
-                        if fee > 0 {
-                            ext_fee_collector::ext(self.fee_collector.clone())
-                                .with_static_gas(FT_TRANSFER_GAS)
-                                .record_fee(self.params.quote.clone(), fee);
+                        self.pending_actions.remove(&mint_msg.action_id);
+                        let storage_deposit = self.action_deposits.remove(&mint_msg.action_id).unwrap_or(0);
+                        if let Err(reason) = self.check_caps(&sender_id, amount.0) {
+                            env::log_str(&format!("Mint refunded: {}", reason));
+                            self.internal_refund_storage(&sender_id, storage_deposit);
+                            return PromiseOrValue::Value(amount);
                         }
                         
-                        self.pending_actions.remove(&msg);
-                        U128(0)
+                        ext_fee_collector::ext(self.fee_collector.clone())
+                            .with_static_gas(FEE_QUERY_GAS)
+                            .get_fee_discount_bps(sender_id.clone(), self.params.quote.clone())
+                            .and(self.registration_query(&sender_id))
+                            .then(
+                                Self::ext(env::current_account_id())
+                                    .with_static_gas(MINT_CALLBACK_GAS)
+                                    .on_mint_discount(sender_id, amount, referrer, U128(storage_deposit))
+                            )
+                            .into()
                     }
-                    _ => amount,
+                    _ => PromiseOrValue::Value(amount),
                 }
             } else {
-                amount
+                PromiseOrValue::Value(amount)
             }
         } else {
-            amount
+            PromiseOrValue::Value(amount)
+        }
+    }
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use near_sdk::test_utils::VMContextBuilder;
+    use near_sdk::{testing_env, RuntimeFeesConfig};
+
+    const ONE: u128 = 1_000_000_000_000_000_000_000_000;
+    const MATURITY: u64 = 7 * 86_400 * 1_000_000_000;
+
+    fn account(id: &str) -> AccountId {
+        id.parse().unwrap()
+    }
+
+    fn set_context(predecessor: &str, block_timestamp: u64, promise_results: Vec<PromiseResult>) {
+        let context = VMContextBuilder::new()
+            .current_account_id(account("market-0.factory.near"))
+            .predecessor_account_id(account(predecessor))
+            .block_timestamp(block_timestamp)
+            .build();
+        testing_env!(
+            context,
+            near_sdk::test_vm_config(),
+            RuntimeFeesConfig::test(),
+            Default::default(),
+            promise_results
+        );
+    }
+
+    // L = 30, K = 50, U = 70, with 30/50/20 bps fees.
+    fn default_params() -> MarketParams {
+        MarketParams {
+            underlying: account("wrap.near"),
+            quote: account("usdc.near"),
+            maturity: MATURITY,
+            strike_k: U128(50 * ONE),
+            lower_bound_l: U128(30 * ONE),
+            upper_bound_u: U128(70 * ONE),
+            mint_fee_bps: 30,
+            settle_fee_bps: 50,
+            redeem_fee_bps: 20,
+            caps: None,
+        }
+    }
+
+    fn setup() -> ForwardMarket {
+        set_context("factory.near", 0, vec![]);
+        ForwardMarket::new(
+            default_params(),
+            account("long-0.factory.near"),
+            account("short-0.factory.near"),
+            account("oracle.near"),
+            account("fees.near"),
+            account("owner.near"),
+            account("guardian.near"),
+            None,
+        )
+    }
+
+    fn registration(registered: bool) -> PromiseResult {
+        let balance = registered.then(|| StorageBalance {
+            total: NearToken::from_millinear(2),
+            available: NearToken::from_yoctonear(0),
+        });
+        PromiseResult::Successful(near_sdk::serde_json::to_vec(&balance).unwrap())
+    }
+
+    // A registered minter, with no fee discount.
+    fn mint(market: &mut ForwardMarket, minter: &str, amount: u128) {
+        let results = vec![PromiseResult::Successful(b"0".to_vec()), registration(true), registration(true)];
+        set_context("market-0.factory.near", 0, results);
+        assert_eq!(market.on_mint_discount(account(minter), U128(amount), None, U128(0)), U128(0));
+    }
+
+    fn settle_at(market: &mut ForwardMarket, price: u128) {
+        let price_data = PriceData { price: U128(price), timestamp: MATURITY, decimals: 24 };
+        let result = near_sdk::serde_json::to_vec(&Some(price_data)).unwrap();
+        set_context("market-0.factory.near", MATURITY, vec![PromiseResult::Successful(result)]);
+        assert!(market.on_price_received());
+    }
+
+    #[test]
+    fn test_preview_settlement_at_mid_range_price() {
+        let market = setup();
+        // Three quarters of the way from L to U. At 24 decimals the products
+        // are far beyond u128.
+        let (long_value, short_value) = market.preview_settlement(U128(60 * ONE), None);
+        assert_eq!(long_value, U128(750_000_000_000_000_000_000_000));
+        assert_eq!(short_value, U128(250_000_000_000_000_000_000_000));
+
+        let (long_value, short_value) = market.preview_settlement(U128(60 * ONE), Some(U128(1_000 * ONE)));
+        assert_eq!(long_value, U128(750 * ONE));
+        assert_eq!(short_value, U128(250 * ONE));
+    }
+
+    #[test]
+    fn test_redeem_quote_includes_settle_fee() {
+        let market = setup();
+        let quote = market.preview_redeem(U128(ONE), U128(0), Some(U128(60 * ONE)), None);
+        assert_eq!(quote.settlement_factor, U128(750_000_000_000_000_000_000_000));
+        assert_eq!(quote.gross_payout, U128(750_000_000_000_000_000_000_000));
+        // 50 bps of the pool at settlement, then 20 bps of what is left
+        assert_eq!(quote.settle_fee, U128(3_750_000_000_000_000_000_000));
+        assert_eq!(quote.redeem_fee, U128(1_492_500_000_000_000_000_000));
+        assert_eq!(quote.net_payout, U128(744_757_500_000_000_000_000_000));
+    }
+
+    #[test]
+    fn test_mint_quote_applies_fee_discount() {
+        let market = setup();
+        for (discount_bps, mint_fee) in [
+            (0u16, 3_000_000_000_000_000_000_000u128),
+            (2500, 2_250_000_000_000_000_000_000),
+            (5000, 1_500_000_000_000_000_000_000),
+            (10_000, 0),
+        ] {
+            let quote = market.preview_mint(U128(ONE), None, Some(discount_bps));
+            assert_eq!(quote.mint_fee, U128(mint_fee));
+            assert_eq!(quote.tokens_out, U128(ONE - mint_fee));
+        }
+    }
+
+    #[test]
+    fn test_redeem_whole_tokens_matches_quote() {
+        let mut market = setup();
+        mint(&mut market, "alice.near", 1_000 * ONE);
+        let net = 1_000 * ONE - 3 * ONE;
+        assert_eq!(market.state.total_collateral, net);
+
+        settle_at(&mut market, 60 * ONE);
+        let quote = market.preview_redeem(U128(10 * ONE), U128(10 * ONE), None, None);
+
+        set_context("market-0.factory.near", MATURITY, vec![PromiseResult::Successful(b"0".to_vec())]);
+        market.on_redeem_discount(account("alice.near"), U128(10 * ONE), U128(10 * ONE));
+
+        let ledger = market.internal_ledger(&account("alice.near"));
+        assert_eq!(ledger.redeemed_payouts, quote.net_payout.0);
+        assert_eq!(ledger.basis_tokens, 2 * net - 20 * ONE);
+        assert_eq!(ledger.cost_basis, 1_000 * ONE - mul_div(1_000 * ONE, 20 * ONE, 2 * net));
+    }
+
+    #[test]
+    fn test_account_position_marks_given_balances() {
+        let mut market = setup();
+        mint(&mut market, "alice.near", 1_000 * ONE);
+        let net = 1_000 * ONE - 3 * ONE;
+
+        set_context("alice.near", 0, vec![]);
+        let position = market.get_account_position(account("alice.near"), U128(net), U128(0), Some(U128(60 * ONE)));
+        assert_eq!(position.cost_basis, U128(1_000 * ONE));
+        assert_eq!(position.mark_value, Some(U128(market.settled_payout(net, 3 * ONE / 4, true))));
+        assert!(position.unrealized_pnl.unwrap().0 < 0);
+    }
+
+    #[test]
+    fn test_settle_fee_leaves_no_shortfall() {
+        let mut market = setup();
+        mint(&mut market, "alice.near", 1_000 * ONE);
+        mint(&mut market, "bob.near", 333 * ONE);
+        settle_at(&mut market, 60 * ONE);
+        assert_eq!(market.get_collateral_shortfall(), U128(0));
+
+        let supply = market.state.long_token_supply;
+        set_context("market-0.factory.near", MATURITY, vec![PromiseResult::Successful(b"0".to_vec())]);
+        market.on_redeem_discount(account("alice.near"), U128(supply), U128(supply));
+        // Whatever rounding leaves behind stays in the market as dust.
+        assert_eq!(market.state.long_token_supply, 0);
+        assert!(market.state.total_collateral < 10);
+    }
+
+    #[test]
+    fn test_mints_in_one_block_keep_their_deposits() {
+        let mut market = setup();
+        for minter in ["alice.near", "bob.near"] {
+            let context = VMContextBuilder::new()
+                .current_account_id(account("market-0.factory.near"))
+                .predecessor_account_id(account(minter))
+                .attached_deposit(NearToken::from_yoctonear(DEFAULT_MINT_STORAGE_DEPOSIT))
+                .build();
+            testing_env!(context);
+            market.create_position(U128(ONE), None);
         }
+        assert_eq!(market.action_deposits.get(&"mint_1".to_string()), Some(DEFAULT_MINT_STORAGE_DEPOSIT));
+        assert_eq!(market.action_deposits.get(&"mint_2".to_string()), Some(DEFAULT_MINT_STORAGE_DEPOSIT));
+        assert_eq!(market.pending_actions.get(&"mint_2".to_string()).unwrap().account, account("bob.near"));
+    }
+
+    #[test]
+    fn test_mint_refunded_when_unregistered_without_deposit() {
+        let mut market = setup();
+        mint(&mut market, "alice.near", 100 * ONE);
+
+        // alice has a ledger entry but has since unregistered from the short token.
+        let results = || vec![PromiseResult::Successful(b"0".to_vec()), registration(true), registration(false)];
+        set_context("market-0.factory.near", 0, results());
+        let refunded = market.on_mint_discount(account("alice.near"), U128(100 * ONE), None, U128(0));
+        assert_eq!(refunded, U128(100 * ONE));
+        assert_eq!(market.state.total_collateral, 100 * ONE - 3 * ONE / 10);
+
+        set_context("market-0.factory.near", 0, results());
+        let deposit = U128(DEFAULT_MINT_STORAGE_DEPOSIT);
+        assert_eq!(market.on_mint_discount(account("alice.near"), U128(100 * ONE), None, deposit), U128(0));
+        assert_eq!(market.state.total_collateral, 2 * (100 * ONE - 3 * ONE / 10));
+    }
+
+    #[test]
+    fn test_owner_can_unblock_reconcile() {
+        let mut market = setup();
+        mint(&mut market, "alice.near", 1_000 * ONE);
+        assert_eq!(market.supply_ops_in_flight, 2);
+
+        // Neither mint resolves, so the guardian is locked out until a reset.
+        set_context("owner.near", 0, vec![]);
+        market.reset_supply_ops_in_flight();
+        assert_eq!(market.supply_ops_in_flight, 0);
+        set_context("guardian.near", 0, vec![]);
+        let _ = market.reconcile_supply();
+    }
+
+    #[test]
+    fn test_migrate_from_legacy_layout() {
+        set_context("market-0.factory.near", 0, vec![]);
+        let mut legacy = LegacyForwardMarket {
+            params: default_params(),
+            state: LegacyMarketState {
+                is_settled: false,
+                settlement_price: None,
+                settlement_factor: None,
+                total_collateral: 500 * ONE,
+                long_token_supply: 500 * ONE,
+                short_token_supply: 500 * ONE,
+                paused_mint: false,
+                paused_settle: true,
+            },
+            long_token: account("long-0.factory.near"),
+            short_token: account("short-0.factory.near"),
+            oracle: account("oracle.near"),
+            fee_collector: account("fees.near"),
+            owner: account("owner.near"),
+            guardian: account("guardian.near"),
+            user_deposits: UnorderedMap::new(b"d"),
+            pending_actions: UnorderedMap::new(b"p"),
+        };
+        legacy.user_deposits.insert(&account("alice.near"), &(500 * ONE));
+        env::state_write(&legacy);
+
+        let market = ForwardMarket::migrate();
+        assert_eq!(ForwardMarket::read_state_version(), STATE_VERSION);
+        assert_eq!(market.state.total_collateral, 500 * ONE);
+        assert!(market.state.paused_settle);
+        assert!(market.get_token_backend() == TokenBackend::Nep141);
+        assert_eq!(market.get_user_deposit(account("alice.near")), U128(500 * ONE));
     }
-}
\ No newline at end of file
+}
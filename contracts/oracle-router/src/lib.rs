@@ -123,7 +123,7 @@ impl OracleRouter {
         };
         
         // Use Rhea's TWAP price method for better manipulation resistance
-        ext_rhea::ext(AccountId::new_unchecked(rhea_account.to_string()))
+        ext_rhea::ext(AccountId::new_unvalidated(rhea_account.to_string()))
             .with_static_gas(Gas::from_tgas(10))
             .get_twap_price(
                 config.rhea_pool_id,
@@ -161,7 +161,7 @@ impl OracleRouter {
         };
         
         if config.use_stable_pool {
-            ext_rhea::ext(AccountId::new_unchecked(rhea_account.to_string()))
+            ext_rhea::ext(AccountId::new_unvalidated(rhea_account.to_string()))
                 .with_static_gas(Gas::from_tgas(10))
                 .get_stable_pool_price(
                     config.rhea_pool_id,
@@ -169,7 +169,7 @@ impl OracleRouter {
                     quote.clone(),
                 )
         } else {
-            ext_rhea::ext(AccountId::new_unchecked(rhea_account.to_string()))
+            ext_rhea::ext(AccountId::new_unvalidated(rhea_account.to_string()))
                 .with_static_gas(Gas::from_tgas(10))
                 .get_twap_price(
                     config.rhea_pool_id,
@@ -0,0 +1,611 @@
+use near_sdk::borsh::{BorshDeserialize, BorshSerialize};
+use near_sdk::collections::{LookupMap, UnorderedMap};
+use near_sdk::json_types::U128;
+use near_sdk::serde::{Deserialize, Serialize};
+use near_sdk::serde_json::json;
+use near_sdk::{
+    assert_one_yocto, env, ext_contract, near, require, AccountId, Gas, NearToken, PanicOnDefault,
+    Promise, PromiseOrValue, PromiseResult,
+};
+
+type Balance = u128;
+
+const MT_ON_TRANSFER_GAS: Gas = Gas::from_tgas(30);
+const MT_RESOLVE_TRANSFER_GAS: Gas = Gas::from_tgas(15);
+const MIGRATE_GAS: Gas = Gas::from_tgas(50);
+const STATE_VERSION: u16 = 1;
+// Upper bounds for an account record and a "<market>:<side>|<account>"
+// balance entry; what is actually charged is measured when they are written.
+const ACCOUNT_STORAGE_BYTES: u64 = 200;
+const BALANCE_ENTRY_STORAGE_BYTES: u64 = 250;
+const STATE_VERSION_KEY: &[u8] = b"STATE_VERSION";
+
+pub type TokenId = String;
+
+#[derive(BorshSerialize, BorshDeserialize, Serialize, Deserialize, Clone, Copy, PartialEq)]
+#[serde(crate = "near_sdk::serde")]
+pub enum Side {
+    Long,
+    Short,
+}
+
+#[derive(BorshSerialize, BorshDeserialize, Serialize, Deserialize, Clone)]
+#[serde(crate = "near_sdk::serde")]
+pub struct PositionTokenInfo {
+    pub token_id: TokenId,
+    pub market: AccountId,
+    pub side: Side,
+    pub archived: bool,
+}
+
+#[derive(BorshSerialize, BorshDeserialize)]
+pub struct AccountStorage {
+    pub total: Balance,
+    pub used: Balance,
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(crate = "near_sdk::serde")]
+pub struct StorageBalance {
+    pub total: U128,
+    pub available: U128,
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(crate = "near_sdk::serde")]
+pub struct StorageBalanceBounds {
+    pub min: U128,
+    pub max: Option<U128>,
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(crate = "near_sdk::serde")]
+pub struct Token {
+    pub token_id: TokenId,
+    pub owner_id: Option<AccountId>,
+}
+
+#[ext_contract(ext_mt_receiver)]
+trait MultiTokenReceiver {
+    fn mt_on_transfer(
+        &mut self,
+        sender_id: AccountId,
+        previous_owner_ids: Vec<AccountId>,
+        token_ids: Vec<TokenId>,
+        amounts: Vec<U128>,
+        msg: String,
+    ) -> PromiseOrValue<Vec<U128>>;
+}
+
+#[ext_contract(ext_self)]
+trait MultiTokenResolver {
+    fn mt_resolve_transfer(
+        &mut self,
+        previous_owner_ids: Vec<AccountId>,
+        receiver_id: AccountId,
+        token_ids: Vec<TokenId>,
+        amounts: Vec<U128>,
+    ) -> Vec<U128>;
+}
+
+#[near(contract_state)]
+#[derive(PanicOnDefault)]
+pub struct PositionToken {
+    factory: AccountId,
+    tokens: UnorderedMap<TokenId, PositionTokenInfo>,
+    balances: LookupMap<String, Balance>,
+    supplies: LookupMap<TokenId, Balance>,
+    // NEP-145 deposits; every balance entry an account holds is paid from its own
+    accounts: LookupMap<AccountId, AccountStorage>,
+}
+
+#[near]
+impl PositionToken {
+    #[init]
+    pub fn new(factory: AccountId) -> Self {
+        assert!(!env::state_exists(), "Already initialized");
+        Self::write_state_version();
+        Self {
+            factory,
+            tokens: UnorderedMap::new(b"t"),
+            balances: LookupMap::new(b"b"),
+            supplies: LookupMap::new(b"s"),
+            accounts: LookupMap::new(b"r"),
+        }
+    }
+
+    pub fn register_market(&mut self, market: AccountId) -> Vec<TokenId> {
+        self.assert_factory();
+        [Side::Long, Side::Short]
+            .iter()
+            .map(|side| {
+                let token_id = Self::token_id(&market, *side);
+                require!(self.tokens.get(&token_id).is_none(), "Token already registered");
+                self.tokens.insert(&token_id, &PositionTokenInfo {
+                    token_id: token_id.clone(),
+                    market: market.clone(),
+                    side: *side,
+                    archived: false,
+                });
+                env::log_str(&format!("Position token {} registered", token_id));
+                token_id
+            })
+            .collect()
+    }
+
+    pub fn archive_market(&mut self, market: AccountId) {
+        self.assert_factory();
+        for side in [Side::Long, Side::Short] {
+            let token_id = Self::token_id(&market, side);
+            let mut info = self.tokens.get(&token_id).expect("Token not registered");
+            info.archived = true;
+            self.tokens.insert(&token_id, &info);
+            env::log_str(&format!(
+                "Position token {} archived with {} unredeemed",
+                token_id,
+                self.supplies.get(&token_id).unwrap_or(0)
+            ));
+        }
+    }
+
+    /// NEAR attached by the market is added to the receiver's storage
+    /// deposit, registering it if needed.
+    #[payable]
+    pub fn mt_mint(&mut self, token_id: TokenId, account_id: AccountId, amount: U128) {
+        self.assert_minter(&token_id);
+        let deposit = env::attached_deposit().as_yoctonear();
+        if deposit > 0 {
+            self.internal_storage_deposit(&account_id, deposit);
+        }
+        self.internal_deposit(&token_id, &account_id, amount.0);
+        let supply = self.supplies.get(&token_id).unwrap_or(0);
+        self.supplies.insert(&token_id, &(supply + amount.0));
+        Self::emit_event("mt_mint", json!([{
+            "owner_id": account_id,
+            "token_ids": [token_id],
+            "amounts": [amount],
+        }]));
+    }
+
+    pub fn mt_burn(&mut self, token_id: TokenId, account_id: AccountId, amount: U128) {
+        self.assert_minter(&token_id);
+        self.internal_withdraw(&token_id, &account_id, amount.0);
+        let supply = self.supplies.get(&token_id).unwrap_or(0);
+        self.supplies.insert(&token_id, &(supply - amount.0));
+        Self::emit_event("mt_burn", json!([{
+            "owner_id": account_id,
+            "token_ids": [token_id],
+            "amounts": [amount],
+        }]));
+    }
+
+    #[payable]
+    pub fn mt_transfer(
+        &mut self,
+        receiver_id: AccountId,
+        token_id: TokenId,
+        amount: U128,
+        approval: Option<(AccountId, u64)>,
+        memo: Option<String>,
+    ) {
+        assert_one_yocto();
+        require!(approval.is_none(), "Approvals are not supported");
+        let sender_id = env::predecessor_account_id();
+        self.internal_transfer(&sender_id, &receiver_id, &[token_id], &[amount], memo);
+    }
+
+    #[payable]
+    pub fn mt_batch_transfer(
+        &mut self,
+        receiver_id: AccountId,
+        token_ids: Vec<TokenId>,
+        amounts: Vec<U128>,
+        approvals: Option<Vec<Option<(AccountId, u64)>>>,
+        memo: Option<String>,
+    ) {
+        assert_one_yocto();
+        require!(approvals.is_none(), "Approvals are not supported");
+        let sender_id = env::predecessor_account_id();
+        self.internal_transfer(&sender_id, &receiver_id, &token_ids, &amounts, memo);
+    }
+
+    #[payable]
+    pub fn mt_transfer_call(
+        &mut self,
+        receiver_id: AccountId,
+        token_id: TokenId,
+        amount: U128,
+        approval: Option<(AccountId, u64)>,
+        memo: Option<String>,
+        msg: String,
+    ) -> PromiseOrValue<Vec<U128>> {
+        self.mt_batch_transfer_call(
+            receiver_id,
+            vec![token_id],
+            vec![amount],
+            approval.map(|approval| vec![Some(approval)]),
+            memo,
+            msg,
+        )
+    }
+
+    #[payable]
+    pub fn mt_batch_transfer_call(
+        &mut self,
+        receiver_id: AccountId,
+        token_ids: Vec<TokenId>,
+        amounts: Vec<U128>,
+        approvals: Option<Vec<Option<(AccountId, u64)>>>,
+        memo: Option<String>,
+        msg: String,
+    ) -> PromiseOrValue<Vec<U128>> {
+        assert_one_yocto();
+        require!(approvals.is_none(), "Approvals are not supported");
+        let sender_id = env::predecessor_account_id();
+        self.internal_transfer(&sender_id, &receiver_id, &token_ids, &amounts, memo);
+
+        let previous_owner_ids = vec![sender_id.clone(); token_ids.len()];
+        ext_mt_receiver::ext(receiver_id.clone())
+            .with_static_gas(MT_ON_TRANSFER_GAS)
+            .mt_on_transfer(
+                sender_id,
+                previous_owner_ids.clone(),
+                token_ids.clone(),
+                amounts.clone(),
+                msg,
+            )
+            .then(
+                Self::ext(env::current_account_id())
+                    .with_static_gas(MT_RESOLVE_TRANSFER_GAS)
+                    .mt_resolve_transfer(previous_owner_ids, receiver_id, token_ids, amounts)
+            )
+            .into()
+    }
+
+    #[private]
+    pub fn mt_resolve_transfer(
+        &mut self,
+        previous_owner_ids: Vec<AccountId>,
+        receiver_id: AccountId,
+        token_ids: Vec<TokenId>,
+        amounts: Vec<U128>,
+    ) -> Vec<U128> {
+        let unused: Vec<u128> = match env::promise_result(0) {
+            PromiseResult::Successful(value) => near_sdk::serde_json::from_slice::<Vec<U128>>(&value)
+                .ok()
+                .filter(|unused| unused.len() == amounts.len())
+                .map(|unused| {
+                    unused
+                        .iter()
+                        .zip(amounts.iter())
+                        .map(|(unused, amount)| unused.0.min(amount.0))
+                        .collect()
+                })
+                .unwrap_or_else(|| amounts.iter().map(|amount| amount.0).collect()),
+            _ => amounts.iter().map(|amount| amount.0).collect(),
+        };
+
+        let mut used = Vec::with_capacity(amounts.len());
+        for (index, token_id) in token_ids.iter().enumerate() {
+            let key = Self::balance_key(token_id, &receiver_id);
+            let receiver_balance = self.balances.get(&key).unwrap_or(0);
+            let mut refund = unused[index].min(receiver_balance);
+            // A sender that has since withdrawn its storage deposit cannot take
+            // the balance back, so it stays with the receiver.
+            if refund > 0 && !self.can_store(token_id, &previous_owner_ids[index]) {
+                env::log_str(&format!(
+                    "Refund of {} {} to {} skipped: no storage deposit",
+                    refund, token_id, previous_owner_ids[index]
+                ));
+                refund = 0;
+            }
+            if refund > 0 {
+                self.internal_withdraw(token_id, &receiver_id, refund);
+                self.internal_deposit(token_id, &previous_owner_ids[index], refund);
+                Self::emit_event("mt_transfer", json!([{
+                    "old_owner_id": receiver_id,
+                    "new_owner_id": previous_owner_ids[index],
+                    "token_ids": [token_id],
+                    "amounts": [U128(refund)],
+                    "memo": "refund",
+                }]));
+            }
+            used.push(U128(amounts[index].0 - refund));
+        }
+        used
+    }
+
+    pub fn mt_token(&self, token_ids: Vec<TokenId>) -> Vec<Option<Token>> {
+        token_ids
+            .into_iter()
+            .map(|token_id| {
+                self.tokens.get(&token_id).map(|_| Token {
+                    token_id,
+                    owner_id: None,
+                })
+            })
+            .collect()
+    }
+
+    pub fn mt_balance_of(&self, account_id: AccountId, token_id: TokenId) -> U128 {
+        U128(self.balances.get(&Self::balance_key(&token_id, &account_id)).unwrap_or(0))
+    }
+
+    pub fn mt_batch_balance_of(&self, account_id: AccountId, token_ids: Vec<TokenId>) -> Vec<U128> {
+        token_ids
+            .iter()
+            .map(|token_id| U128(self.balances.get(&Self::balance_key(token_id, &account_id)).unwrap_or(0)))
+            .collect()
+    }
+
+    pub fn mt_supply(&self, token_id: TokenId) -> Option<U128> {
+        self.tokens
+            .get(&token_id)
+            .map(|_| U128(self.supplies.get(&token_id).unwrap_or(0)))
+    }
+
+    pub fn mt_batch_supply(&self, token_ids: Vec<TokenId>) -> Vec<Option<U128>> {
+        token_ids
+            .into_iter()
+            .map(|token_id| self.mt_supply(token_id))
+            .collect()
+    }
+
+    pub fn get_position_token(&self, token_id: TokenId) -> Option<PositionTokenInfo> {
+        self.tokens.get(&token_id)
+    }
+
+    pub fn get_market_token_ids(&self, market: AccountId) -> Vec<TokenId> {
+        vec![Self::token_id(&market, Side::Long), Self::token_id(&market, Side::Short)]
+    }
+
+    pub fn upgrade(&mut self) -> Promise {
+        self.assert_factory();
+        let code = env::input().expect("No code provided");
+        env::log_str(&format!("Position token {} upgrading", env::current_account_id()));
+        Promise::new(env::current_account_id())
+            .deploy_contract(code)
+            .function_call("migrate".to_string(), Vec::new(), NearToken::from_yoctonear(0), MIGRATE_GAS)
+    }
+
+    #[private]
+    #[init(ignore_state)]
+    pub fn migrate() -> Self {
+        let version = Self::read_state_version();
+        require!(version <= STATE_VERSION, "State version is newer than code");
+        let token: Self = env::state_read().expect("Failed to read state");
+        Self::write_state_version();
+        env::log_str(&format!(
+            "Position token state migrated from version {} to {}",
+            version, STATE_VERSION
+        ));
+        token
+    }
+
+    pub fn get_state_version(&self) -> u16 {
+        Self::read_state_version()
+    }
+
+    #[payable]
+    pub fn storage_deposit(&mut self, account_id: Option<AccountId>, registration_only: Option<bool>) -> StorageBalance {
+        let account_id = account_id.unwrap_or_else(env::predecessor_account_id);
+        let deposit = env::attached_deposit().as_yoctonear();
+        let registered = self.accounts.contains_key(&account_id);
+        if registration_only.unwrap_or(false) {
+            let min = self.storage_balance_bounds().min.0;
+            let kept = if registered {
+                0
+            } else {
+                require!(deposit >= min, "Deposit is less than the minimum storage balance");
+                min
+            };
+            if kept > 0 {
+                self.internal_storage_deposit(&account_id, kept);
+            }
+            if deposit > kept {
+                Promise::new(env::predecessor_account_id()).transfer(NearToken::from_yoctonear(deposit - kept));
+            }
+        } else {
+            require!(
+                registered || deposit >= self.storage_balance_bounds().min.0,
+                "Deposit is less than the minimum storage balance"
+            );
+            self.internal_storage_deposit(&account_id, deposit);
+        }
+        self.storage_balance_of(account_id).unwrap()
+    }
+
+    #[payable]
+    pub fn storage_withdraw(&mut self, amount: Option<U128>) -> StorageBalance {
+        assert_one_yocto();
+        let account_id = env::predecessor_account_id();
+        let mut storage = self.accounts.get(&account_id).expect("Account not registered");
+        let available = storage.total - storage.used;
+        let amount = amount.map_or(available, |amount| amount.0);
+        require!(amount <= available, "Amount exceeds the available storage balance");
+        
+        storage.total -= amount;
+        self.accounts.insert(&account_id, &storage);
+        if amount > 0 {
+            Promise::new(account_id.clone()).transfer(NearToken::from_yoctonear(amount));
+        }
+        self.storage_balance_of(account_id).unwrap()
+    }
+
+    /// Only accounts that hold no paid-for balances can unregister.
+    #[payable]
+    pub fn storage_unregister(&mut self, force: Option<bool>) -> bool {
+        assert_one_yocto();
+        require!(!force.unwrap_or(false), "Forced unregistration is not supported");
+        let account_id = env::predecessor_account_id();
+        let storage = match self.accounts.get(&account_id) {
+            Some(storage) => storage,
+            None => return false,
+        };
+        let initial_storage = env::storage_usage();
+        self.accounts.remove(&account_id);
+        let record_cost = (initial_storage - env::storage_usage()) as Balance * env::storage_byte_cost().as_yoctonear();
+        require!(storage.used <= record_cost, "Account still holds position balances");
+        
+        Promise::new(account_id.clone()).transfer(NearToken::from_yoctonear(storage.total));
+        env::log_str(&format!("Account {} unregistered", account_id));
+        true
+    }
+
+    pub fn storage_balance_bounds(&self) -> StorageBalanceBounds {
+        StorageBalanceBounds {
+            // The account record plus the long and short entries of one market
+            min: U128((ACCOUNT_STORAGE_BYTES + 2 * BALANCE_ENTRY_STORAGE_BYTES) as Balance * env::storage_byte_cost().as_yoctonear()),
+            max: None,
+        }
+    }
+
+    pub fn storage_balance_of(&self, account_id: AccountId) -> Option<StorageBalance> {
+        self.accounts.get(&account_id).map(|storage| StorageBalance {
+            total: U128(storage.total),
+            available: U128(storage.total - storage.used),
+        })
+    }
+
+    fn internal_transfer(
+        &mut self,
+        sender_id: &AccountId,
+        receiver_id: &AccountId,
+        token_ids: &[TokenId],
+        amounts: &[U128],
+        memo: Option<String>,
+    ) {
+        require!(sender_id != receiver_id, "Sender and receiver must differ");
+        require!(!token_ids.is_empty(), "No tokens to transfer");
+        require!(token_ids.len() == amounts.len(), "Token ids and amounts must match");
+        for (token_id, amount) in token_ids.iter().zip(amounts.iter()) {
+            let info = self.tokens.get(token_id).expect("Token not registered");
+            require!(!info.archived, "Token is archived");
+            require!(amount.0 > 0, "Amount must be positive");
+            self.internal_withdraw(token_id, sender_id, amount.0);
+            self.internal_deposit(token_id, receiver_id, amount.0);
+        }
+        Self::emit_event("mt_transfer", json!([{
+            "old_owner_id": sender_id,
+            "new_owner_id": receiver_id,
+            "token_ids": token_ids,
+            "amounts": amounts,
+            "memo": memo,
+        }]));
+    }
+
+    fn internal_storage_deposit(&mut self, account_id: &AccountId, amount: Balance) {
+        match self.accounts.get(account_id) {
+            Some(mut storage) => {
+                storage.total += amount;
+                self.accounts.insert(account_id, &storage);
+            }
+            None => {
+                let initial_storage = env::storage_usage();
+                self.accounts.insert(account_id, &AccountStorage { total: amount, used: 0 });
+                let used = (env::storage_usage() - initial_storage) as Balance * env::storage_byte_cost().as_yoctonear();
+                require!(amount >= used, "Deposit does not cover account registration");
+                self.accounts.insert(account_id, &AccountStorage { total: amount, used });
+                env::log_str(&format!("Account {} registered", account_id));
+            }
+        }
+    }
+
+    fn can_store(&self, token_id: &TokenId, account_id: &AccountId) -> bool {
+        self.balances.contains_key(&Self::balance_key(token_id, account_id))
+            || self.accounts.get(account_id).map_or(false, |storage| {
+                storage.total - storage.used >= BALANCE_ENTRY_STORAGE_BYTES as Balance * env::storage_byte_cost().as_yoctonear()
+            })
+    }
+
+    // A new balance entry is paid from the holder's storage deposit and the
+    // cost goes back to it when the entry is removed.
+    fn internal_deposit(&mut self, token_id: &TokenId, account_id: &AccountId, amount: Balance) {
+        let key = Self::balance_key(token_id, account_id);
+        match self.balances.get(&key) {
+            Some(balance) => {
+                self.balances.insert(&key, &balance.checked_add(amount).expect("Balance overflow"));
+            }
+            None => {
+                let mut storage = self.accounts.get(account_id).unwrap_or_else(|| {
+                    env::panic_str(&format!("Account {} is not registered", account_id))
+                });
+                let initial_storage = env::storage_usage();
+                self.balances.insert(&key, &amount);
+                let cost = (env::storage_usage() - initial_storage) as Balance * env::storage_byte_cost().as_yoctonear();
+                require!(
+                    storage.total - storage.used >= cost,
+                    format!("Insufficient storage deposit for {}", account_id)
+                );
+                storage.used += cost;
+                self.accounts.insert(account_id, &storage);
+            }
+        }
+    }
+
+    fn internal_withdraw(&mut self, token_id: &TokenId, account_id: &AccountId, amount: Balance) {
+        let key = Self::balance_key(token_id, account_id);
+        let balance = self.balances.get(&key).unwrap_or(0);
+        require!(balance >= amount, "Insufficient balance");
+        if balance == amount {
+            let initial_storage = env::storage_usage();
+            self.balances.remove(&key);
+            let released = (initial_storage - env::storage_usage()) as Balance * env::storage_byte_cost().as_yoctonear();
+            // Entries from before storage accounting were never charged.
+            if let Some(mut storage) = self.accounts.get(account_id) {
+                storage.used = storage.used.saturating_sub(released);
+                self.accounts.insert(account_id, &storage);
+            }
+        } else {
+            self.balances.insert(&key, &(balance - amount));
+        }
+    }
+
+    fn emit_event(event: &str, data: near_sdk::serde_json::Value) {
+        env::log_str(&format!(
+            "EVENT_JSON:{}",
+            json!({
+                "standard": "nep245",
+                "version": "1.0.0",
+                "event": event,
+                "data": data,
+            })
+        ));
+    }
+
+    fn token_id(market: &AccountId, side: Side) -> TokenId {
+        match side {
+            Side::Long => format!("{}:long", market),
+            Side::Short => format!("{}:short", market),
+        }
+    }
+
+    fn balance_key(token_id: &TokenId, account_id: &AccountId) -> String {
+        format!("{}|{}", token_id, account_id)
+    }
+
+    fn read_state_version() -> u16 {
+        env::storage_read(STATE_VERSION_KEY)
+            .map(|bytes| u16::from_le_bytes(bytes.try_into().expect("Invalid state version")))
+            .unwrap_or(0)
+    }
+
+    fn write_state_version() {
+        env::storage_write(STATE_VERSION_KEY, &STATE_VERSION.to_le_bytes());
+    }
+
+    fn assert_factory(&self) {
+        require!(
+            env::predecessor_account_id() == self.factory,
+            "Only factory can call this method"
+        );
+    }
+
+    fn assert_minter(&self, token_id: &TokenId) {
+        let info = self.tokens.get(token_id).expect("Token not registered");
+        require!(
+            env::predecessor_account_id() == info.market,
+            "Only the owning market can mint/burn"
+        );
+    }
+}
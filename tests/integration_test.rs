@@ -1,16 +1,17 @@
 #[cfg(test)]
 mod tests {
-    use near_sdk::json_types::U128;
+    use near_sdk::json_types::{Base58CryptoHash, Base64VecU8, U128};
     use near_sdk::serde_json::json;
     use near_sdk::AccountId;
     use near_sdk_sim::{deploy, init_simulator, to_yocto, ContractAccount, UserAccount};
 
     const FORWARD_FACTORY_WASM: &[u8] = include_bytes!("../res/forward-factory.wasm");
     const FORWARD_MARKET_WASM: &[u8] = include_bytes!("../res/forward-market.wasm");
-    const LONG_TOKEN_WASM: &[u8] = include_bytes!("../res/long-token.wasm");
-    const SHORT_TOKEN_WASM: &[u8] = include_bytes!("../res/short-token.wasm");
+    const CLAIM_TOKEN_WASM: &[u8] = include_bytes!("../res/claim-token.wasm");
     const FEE_COLLECTOR_WASM: &[u8] = include_bytes!("../res/fee-collector.wasm");
     const ORACLE_ROUTER_WASM: &[u8] = include_bytes!("../res/oracle-router.wasm");
+    const CODE_CHUNK_SIZE: usize = 200_000;
+    const MATURITY_NS: u64 = 7 * 86_400 * 1_000_000_000;
 
     fn init() -> (UserAccount, ContractAccount, ContractAccount, ContractAccount) {
         let root = init_simulator(None);
@@ -28,7 +29,7 @@ mod tests {
             contract_id: "fee_collector".to_string(),
             bytes: &FEE_COLLECTOR_WASM,
             signer_account: root,
-            init_method: new(root.account_id(), root.account_id())
+            init_method: new(root.account_id(), root.account_id(), root.account_id())
         );
 
         let factory = deploy!(
@@ -47,24 +48,120 @@ mod tests {
         (root, factory, oracle, fee_collector)
     }
 
-    #[test]
-    fn test_deploy_market() {
-        let (root, factory, oracle, fee_collector) = init();
+    fn upload_code(root: &UserAccount, factory: &ContractAccount, code: &[u8]) -> Base58CryptoHash {
+        let code_hash = Base58CryptoHash::from(near_sdk::env::sha256_array(code));
+        
+        let res = root.call(
+            factory.account_id(),
+            "start_code_upload",
+            &json!({ "code_hash": code_hash, "size": code.len() as u64 }).to_string().into_bytes(),
+            near_sdk_sim::DEFAULT_GAS,
+            0,
+        );
+        assert!(res.is_ok());
+        
+        for chunk in code.chunks(CODE_CHUNK_SIZE) {
+            let res = root.call(
+                factory.account_id(),
+                "upload_code_chunk",
+                &json!({ "code_hash": code_hash, "chunk": Base64VecU8::from(chunk.to_vec()) })
+                    .to_string()
+                    .into_bytes(),
+                near_sdk_sim::DEFAULT_GAS,
+                0,
+            );
+            assert!(res.is_ok());
+        }
         
         let res = root.call(
             factory.account_id(),
-            "set_contract_codes",
+            "finalize_code_upload",
+            &json!({ "code_hash": code_hash }).to_string().into_bytes(),
+            near_sdk_sim::DEFAULT_GAS,
+            0,
+        );
+        assert!(res.is_ok());
+        
+        code_hash
+    }
+
+    fn publish_release(root: &UserAccount, factory: &ContractAccount) {
+        let market_code_hash = upload_code(root, factory, FORWARD_MARKET_WASM);
+        let claim_token_code_hash = upload_code(root, factory, CLAIM_TOKEN_WASM);
+        
+        let res = root.call(
+            factory.account_id(),
+            "publish_release",
             &json!({
-                "market_code": FORWARD_MARKET_WASM.to_vec(),
-                "long_token_code": LONG_TOKEN_WASM.to_vec(),
-                "short_token_code": SHORT_TOKEN_WASM.to_vec(),
+                "version": "1.0.0",
+                "market_code_hash": market_code_hash,
+                "claim_token_code_hash": claim_token_code_hash,
+                "release_notes": "Initial release",
             }).to_string().into_bytes(),
             near_sdk_sim::DEFAULT_GAS,
             0,
         );
         assert!(res.is_ok());
+    }
+
+    fn approve_pair(root: &UserAccount, factory: &ContractAccount, oracle: &ContractAccount) {
+        root.call(
+            oracle.account_id(),
+            "configure_oracle",
+            &json!({
+                "underlying": "wrap.near",
+                "quote": "usdc.near",
+                "config": {
+                    "rhea_pool_id": 1,
+                    "twap_window": 300,
+                    "max_staleness": 600,
+                    "max_deviation_bps": 500,
+                    "use_stable_pool": false,
+                },
+            }).to_string().into_bytes(),
+            near_sdk_sim::DEFAULT_GAS,
+            0,
+        ).assert_success();
+        
+        root.call(
+            factory.account_id(),
+            "approve_quote",
+            &json!({ "quote": "usdc.near" }).to_string().into_bytes(),
+            near_sdk_sim::DEFAULT_GAS,
+            0,
+        ).assert_success();
+        
+        root.call(
+            factory.account_id(),
+            "approve_underlying",
+            &json!({ "underlying": "wrap.near", "quote": "usdc.near" }).to_string().into_bytes(),
+            near_sdk_sim::DEFAULT_GAS,
+            0,
+        ).assert_success();
+    }
+
+    fn default_params() -> serde_json::Value {
+        json!({
+            "underlying": "wrap.near",
+            "quote": "usdc.near",
+            "maturity": MATURITY_NS,
+            "strike_k": U128(50_000_000_000_000_000_000_000_000u128),
+            "lower_bound_l": U128(30_000_000_000_000_000_000_000_000u128),
+            "upper_bound_u": U128(70_000_000_000_000_000_000_000_000u128),
+            "mint_fee_bps": 30,
+            "settle_fee_bps": 50,
+            "redeem_fee_bps": 20,
+        })
+    }
 
-        let maturity = 1700000000u64;
+    #[test]
+    fn test_deploy_market() {
+        let (root, factory, oracle, fee_collector) = init();
+        
+        publish_release(&root, &factory);
+        approve_pair(&root, &factory, &oracle);
+
+        let maturity = MATURITY_NS;
         let params = json!({
             "underlying": "wrap.near",
             "quote": "usdc.near",
@@ -82,7 +179,7 @@ mod tests {
             "deploy_market",
             &json!({ "params": params }).to_string().into_bytes(),
             near_sdk_sim::DEFAULT_GAS,
-            to_yocto("5"),
+            to_yocto("20"),
         );
         assert!(res.is_ok());
 
@@ -90,28 +187,134 @@ mod tests {
             .view(factory.account_id(), "get_market_count", &[])
             .unwrap_json();
         assert_eq!(market_count, 1);
+        
+        let markets: Vec<serde_json::Value> = root
+            .view(
+                factory.account_id(),
+                "get_all_markets",
+                &json!({ "from_index": 0, "limit": 10 }).to_string().into_bytes(),
+            )
+            .unwrap_json();
+        assert_eq!(markets[0]["code_version"], "1.0.0");
     }
 
     #[test]
-    fn test_market_lifecycle() {
-        let (root, factory, oracle, fee_collector) = init();
+    fn test_deploy_rejects_unlisted_quote() {
+        let (root, factory, oracle, _fee_collector) = init();
         
-        root.call(
+        publish_release(&root, &factory);
+        approve_pair(&root, &factory, &oracle);
+
+        let params = json!({
+            "underlying": "wrap.near",
+            "quote": "usdt.near",
+            "maturity": MATURITY_NS,
+            "strike_k": U128(50_000_000_000_000_000_000_000_000u128),
+            "lower_bound_l": U128(30_000_000_000_000_000_000_000_000u128),
+            "upper_bound_u": U128(70_000_000_000_000_000_000_000_000u128),
+            "mint_fee_bps": 30,
+            "settle_fee_bps": 50,
+            "redeem_fee_bps": 20,
+        });
+
+        let reason: Option<String> = root
+            .view(
+                factory.account_id(),
+                "check_market_params",
+                &json!({ "params": params }).to_string().into_bytes(),
+            )
+            .unwrap_json();
+        assert_eq!(reason, Some("Quote token usdt.near is not approved".to_string()));
+
+        let res = root.call(
             factory.account_id(),
-            "set_contract_codes",
-            &json!({
-                "market_code": FORWARD_MARKET_WASM.to_vec(),
-                "long_token_code": LONG_TOKEN_WASM.to_vec(),
-                "short_token_code": SHORT_TOKEN_WASM.to_vec(),
-            }).to_string().into_bytes(),
+            "deploy_market",
+            &json!({ "params": params }).to_string().into_bytes(),
+            near_sdk_sim::DEFAULT_GAS,
+            to_yocto("20"),
+        );
+        assert!(!res.is_ok());
+    }
+
+    #[test]
+    fn test_deploy_rejects_zero_caps() {
+        let (root, factory, oracle, _fee_collector) = init();
+        
+        publish_release(&root, &factory);
+        approve_pair(&root, &factory, &oracle);
+
+        let params = json!({
+            "underlying": "wrap.near",
+            "quote": "usdc.near",
+            "maturity": MATURITY_NS,
+            "strike_k": U128(50_000_000_000_000_000_000_000_000u128),
+            "lower_bound_l": U128(30_000_000_000_000_000_000_000_000u128),
+            "upper_bound_u": U128(70_000_000_000_000_000_000_000_000u128),
+            "mint_fee_bps": 30,
+            "settle_fee_bps": 50,
+            "redeem_fee_bps": 20,
+            "caps": { "max_mint_per_account": U128(0) },
+        });
+
+        let reason: Option<String> = root
+            .view(
+                factory.account_id(),
+                "check_market_params",
+                &json!({ "params": params }).to_string().into_bytes(),
+            )
+            .unwrap_json();
+        assert_eq!(reason, Some("Caps must be positive".to_string()));
+    }
+
+    #[test]
+    fn test_nep245_deploy_requires_position_token() {
+        let (root, factory, oracle, _fee_collector) = init();
+        
+        publish_release(&root, &factory);
+        approve_pair(&root, &factory, &oracle);
+
+        let params = json!({
+            "underlying": "wrap.near",
+            "quote": "usdc.near",
+            "maturity": MATURITY_NS,
+            "strike_k": U128(50_000_000_000_000_000_000_000_000u128),
+            "lower_bound_l": U128(30_000_000_000_000_000_000_000_000u128),
+            "upper_bound_u": U128(70_000_000_000_000_000_000_000_000u128),
+            "mint_fee_bps": 30,
+            "settle_fee_bps": 50,
+            "redeem_fee_bps": 20,
+        });
+
+        let res = root.call(
+            factory.account_id(),
+            "deploy_market",
+            &json!({ "params": params, "token_backend": "Nep245" }).to_string().into_bytes(),
+            near_sdk_sim::DEFAULT_GAS,
+            to_yocto("10"),
+        );
+        assert!(!res.is_ok());
+
+        let res = root.call(
+            factory.account_id(),
+            "set_default_token_backend",
+            &json!({ "token_backend": "Nep245" }).to_string().into_bytes(),
             near_sdk_sim::DEFAULT_GAS,
             0,
         );
+        assert!(!res.is_ok());
+    }
+
+    #[test]
+    fn test_market_lifecycle() {
+        let (root, factory, oracle, fee_collector) = init();
+        
+        publish_release(&root, &factory);
+        approve_pair(&root, &factory, &oracle);
 
         let params = json!({
             "underlying": "wrap.near",
             "quote": "usdc.near",
-            "maturity": 1700000000u64,
+            "maturity": MATURITY_NS,
             "strike_k": U128(50_000_000_000_000_000_000_000_000u128),
             "lower_bound_l": U128(30_000_000_000_000_000_000_000_000u128),
             "upper_bound_u": U128(70_000_000_000_000_000_000_000_000u128),
@@ -125,7 +328,7 @@ mod tests {
             "deploy_market",
             &json!({ "params": params }).to_string().into_bytes(),
             near_sdk_sim::DEFAULT_GAS,
-            to_yocto("5"),
+            to_yocto("20"),
         );
 
         let markets: Vec<serde_json::Value> = root
@@ -187,4 +390,4 @@ mod tests {
         let redeem_fee = (amount * redeem_fee_bps) / 10000;
         assert_eq!(redeem_fee, 2_000_000_000_000_000_000_000);
     }
-}
\ No newline at end of file
+}